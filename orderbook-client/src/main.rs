@@ -1,22 +1,24 @@
 use orderbook_common::*;
 
-use anyhow::{anyhow, Context, Result};
-use futures::SinkExt;
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
-use tokio::io::AsyncWrite;
+use anyhow::{Context, Result};
+use futures::{SinkExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::UnixStream;
 use tokio_serde::formats::SymmetricalJson;
 use tokio_serde::SymmetricallyFramed;
-use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let commands = parse_input()?;
+    let input_file = std::env::args().nth(1).context("Missing input file")?;
+    let commands = input::read_file(input_file)?;
     let stream = UnixStream::connect(SOCKET).await?;
-    send(stream, &commands).await?;
-    Ok(())
+    let (reader, writer) = stream.into_split();
+    // Responses are read while the commands are still being sent, otherwise both sides could
+    // end up waiting for each other to read.
+    let responses = tokio::spawn(receive(reader));
+    send(writer, &commands).await?;
+    responses.await?
 }
 
 async fn send<T: AsyncWrite + Unpin>(io: T, commands: &[Command]) -> Result<()> {
@@ -28,63 +30,80 @@ async fn send<T: AsyncWrite + Unpin>(io: T, commands: &[Command]) -> Result<()>
     Ok(())
 }
 
-// Primitive csv reader. Rewrite with regexps or parser combinators.
-fn parse_input() -> Result<Vec<Command>> {
-    let mut commands = Vec::new();
-    let input_file = std::env::args().nth(1).context("Missing input file")?;
-    for line in read_lines(input_file)? {
-        let line = line?;
-        let words = line.split(',').collect::<Vec<_>>();
-        if words.is_empty() {
-            continue;
+/// Report the responses of the server until it closes the connection.
+async fn receive<T: AsyncRead + Unpin>(io: T) -> Result<()> {
+    let transport = FramedRead::new(io, LengthDelimitedCodec::new());
+    let mut framed = SymmetricallyFramed::new(transport, SymmetricalJson::<Response>::default());
+    while let Some(response) = framed.try_next().await? {
+        match response {
+            Response::Accepted {
+                user_id,
+                user_order_id,
+                order_id,
+            } => println!("Accepted {}, {} as order {}", user_id, user_order_id, order_id),
+            Response::Trade {
+                trade_id,
+                symbol,
+                price,
+                quantity,
+                user_id_buy,
+                user_order_id_buy,
+                order_id_buy,
+                user_id_sell,
+                user_order_id_sell,
+                order_id_sell,
+                aggressor,
+                fee_buy,
+                fee_sell,
+                ..
+            } => println!(
+                "Trade {} in {}: {} at {}, bought by {}, {} (order {}), sold by {}, {} (order {}), aggressor {}, fees {} and {}",
+                trade_id,
+                symbol,
+                quantity,
+                price,
+                user_id_buy,
+                user_order_id_buy,
+                order_id_buy,
+                user_id_sell,
+                user_order_id_sell,
+                order_id_sell,
+                aggressor.unwrap_or('-'),
+                fee_buy,
+                fee_sell
+            ),
+            Response::Rejected {
+                user_id,
+                user_order_id,
+                reason,
+            } => eprintln!("Rejected {}, {}: {}", user_id, user_order_id, reason),
+            Response::AdminAck { command } => println!("Acknowledged {:?}", command),
+            Response::AdminRejected { command, reason } => {
+                eprintln!("Refused {:?}: {}", command, reason)
+            }
+            Response::Positions { user_id, positions } => {
+                for position in positions {
+                    let mark_price = match position.mark_price {
+                        Some(price) => price.to_string(),
+                        None => "-".to_string(),
+                    };
+                    println!(
+                        "Position {}, {}: {} at {}, realized {}, marked at {}, unrealized {}",
+                        user_id,
+                        position.symbol,
+                        position.quantity,
+                        position.average_cost,
+                        position.realized_pnl,
+                        mark_price,
+                        position.unrealized_pnl
+                    );
+                }
+            }
+            Response::Shutdown => {
+                eprintln!("The server is shutting down");
+                break;
+            }
         }
-        // The very first character of non-empty line determines the command.
-        let command = match words[0].chars().next() {
-            Some('#') | None => continue,
-            Some('N') => parse_new_command(&words),
-            Some('C') => parse_cancel_command(&words),
-            Some('F') => parse_flush_command(),
-            _ => return Err(anyhow!("Unecognized command")),
-        }?;
-        commands.push(command);
     }
-    Ok(commands)
-}
-
-fn parse_new_command(words: &[&str]) -> Result<Command> {
-    let user_id = words[1].trim().parse()?;
-    let symbol = words[2].trim().to_string();
-    let price = words[3].trim().parse()?;
-    let quantity = words[4].trim().parse()?;
-    let side = words[5].trim().chars().next().unwrap();
-    let user_order_id = words[6].trim().parse()?;
-    Ok(Command::New(NewOrder {
-        user_id,
-        user_order_id,
-        symbol,
-        price,
-        quantity,
-        side,
-    }))
-}
-
-fn parse_cancel_command(words: &[&str]) -> Result<Command> {
-    let user_id = words[1].trim().parse()?;
-    let user_order_id = words[2].trim().parse()?;
-    Ok(Command::Cancel(CancelOrder {
-        user_id,
-        user_order_id,
-    }))
-}
-
-fn parse_flush_command() -> Result<Command> {
-    Ok(Command::Flush)
-}
-
-fn read_lines<P>(filename: P) -> Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+    Ok(())
 }
@@ -0,0 +1,229 @@
+//! Parser of the CSV input format of the market commands.
+
+use crate::{
+    AdminCommand, AdminRequest, CancelOrder, Command, Deposit, Logon, MassCancel, NewOrder, Peg,
+    PegReference, Phase, PhaseChange, PositionQuery, PostOnly, Trail,
+};
+
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+/// Read all commands from a CSV file.
+pub fn read_file(path: impl AsRef<Path>) -> Result<Vec<Command>> {
+    let file = File::open(path)?;
+    let mut commands = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        if let Some(command) = parse_line(&line?)? {
+            commands.push(command);
+        }
+    }
+    Ok(commands)
+}
+
+/// Parse a single line of the CSV input. Empty lines and comments produce no command.
+pub fn parse_line(line: &str) -> Result<Option<Command>> {
+    // Primitive csv reader. Rewrite with regexps or parser combinators.
+    let words = line.split(',').collect::<Vec<_>>();
+    // The very first character of non-empty line determines the command.
+    let command = match words[0].trim().chars().next() {
+        Some('#') | None => return Ok(None),
+        Some('N') => parse_new_command(&words),
+        Some('C') => parse_cancel_command(&words),
+        Some('F') => parse_flush_command(),
+        Some('Q') => parse_positions_command(&words),
+        Some('M') => parse_mass_cancel_command(&words),
+        Some('L') => parse_logon_command(&words),
+        Some('H') => Ok(Command::Heartbeat),
+        Some('X') => parse_admin_command(&words),
+        _ => return Err(anyhow!("Unecognized command")),
+    }?;
+    Ok(Some(command))
+}
+
+fn parse_new_command(words: &[&str]) -> Result<Command> {
+    if words.len() < 7 {
+        return Err(anyhow!("Too few fields in a new order"));
+    }
+    let user_id = words[1].trim().parse()?;
+    let symbol = words[2].trim().to_string();
+    let price = words[3].trim().parse()?;
+    let quantity = words[4].trim().parse()?;
+    let side = words[5]
+        .trim()
+        .chars()
+        .next()
+        .ok_or_else(|| anyhow!("Missing side"))?;
+    let user_order_id = words[6].trim().parse()?;
+    let mut order = NewOrder {
+        user_id,
+        user_order_id,
+        symbol,
+        price,
+        quantity,
+        side,
+        ..Default::default()
+    };
+    for option in &words[7..] {
+        parse_order_option(option.trim(), &mut order)?;
+    }
+    Ok(Command::New(order))
+}
+
+/// Parse an optional `key=value` field of a new order, following the mandatory fields.
+fn parse_order_option(option: &str, order: &mut NewOrder) -> Result<()> {
+    let (key, value) = option.split_once('=').unwrap_or((option, ""));
+    match key.trim() {
+        "" => (),
+        "stop" => order.stop_price = Some(value.trim().parse()?),
+        "display" => order.display_quantity = Some(value.trim().parse()?),
+        "post_only" => {
+            order.post_only = match value.trim() {
+                "" | "reject" => Some(PostOnly::Reject),
+                "reprice" => Some(PostOnly::Reprice),
+                _ => return Err(anyhow!("Invalid post-only mode {}", value)),
+            }
+        }
+        "peg" => order.peg = Some(parse_peg(value.trim())?),
+        "min" => order.min_quantity = Some(value.trim().parse()?),
+        "aon" => order.all_or_none = true,
+        "hidden" => order.hidden = true,
+        "trail" => {
+            let value = value.trim();
+            order.trail = Some(match value.strip_suffix('%') {
+                Some(percent) => Trail::Percent(percent.parse()?),
+                None => Trail::Amount(value.parse()?),
+            })
+        }
+        _ => return Err(anyhow!("Unrecognized order option {}", option)),
+    }
+    Ok(())
+}
+
+/// Parse a peg written as `reference[:offset]`, e.g. `midpoint` or `market:-1`.
+fn parse_peg(value: &str) -> Result<Peg> {
+    let (reference, offset) = value.split_once(':').unwrap_or((value, "0"));
+    let reference = match reference {
+        "primary" => PegReference::Primary,
+        "market" => PegReference::Market,
+        "midpoint" => PegReference::Midpoint,
+        _ => return Err(anyhow!("Invalid peg reference {}", reference)),
+    };
+    Ok(Peg {
+        reference,
+        offset: offset.parse()?,
+    })
+}
+
+fn parse_cancel_command(words: &[&str]) -> Result<Command> {
+    if words.len() < 3 {
+        return Err(anyhow!("Too few fields in a cancel order"));
+    }
+    let user_id = words[1].trim().parse()?;
+    let user_order_id = words[2].trim().parse()?;
+    Ok(Command::Cancel(CancelOrder {
+        user_id,
+        user_order_id,
+    }))
+}
+
+fn parse_flush_command() -> Result<Command> {
+    Ok(Command::Flush)
+}
+
+fn parse_positions_command(words: &[&str]) -> Result<Command> {
+    if words.len() < 2 {
+        return Err(anyhow!("Too few fields in a positions query"));
+    }
+    let user_id = words[1].trim().parse()?;
+    Ok(Command::QueryPositions(PositionQuery { user_id }))
+}
+
+/// Parse a mass cancel. Each of the user id, the symbol and the side may be left out with `-`,
+/// or by ending the line early.
+fn parse_mass_cancel_command(words: &[&str]) -> Result<Command> {
+    let field = |position: usize| {
+        words
+            .get(position)
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty() && *word != "-")
+    };
+    Ok(Command::MassCancel(MassCancel {
+        user_id: field(1).map(str::parse).transpose()?,
+        symbol: field(2).map(ToString::to_string),
+        side: field(3).and_then(|side| side.chars().next()),
+    }))
+}
+
+/// Parse a logon, whose options follow as `cancel_on_disconnect` and `heartbeat=<secs>`.
+fn parse_logon_command(words: &[&str]) -> Result<Command> {
+    let mut logon = Logon::default();
+    for option in &words[1..] {
+        let (key, value) = option.split_once('=').unwrap_or((option, ""));
+        match key.trim() {
+            "" => (),
+            "cancel_on_disconnect" => logon.cancel_on_disconnect = true,
+            "heartbeat" => logon.heartbeat_secs = Some(value.trim().parse()?),
+            _ => return Err(anyhow!("Unrecognized logon option {}", option.trim())),
+        }
+    }
+    Ok(Command::Logon(logon))
+}
+
+/// Parse an admin command `X, <token>, <action>[, <argument>]`, where the action is `halt` or
+/// `resume` with an optional symbol, the whole market if there is none, `kill` or `enable` with
+/// a user id, `deposit` with a user id followed by the asset and the amount, or `phase` with a
+/// symbol followed by the phase.
+fn parse_admin_command(words: &[&str]) -> Result<Command> {
+    if words.len() < 3 {
+        return Err(anyhow!("Too few fields in an admin command"));
+    }
+    let token = words[1].trim().to_string();
+    let argument = words
+        .get(3)
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty());
+    let user_id = || -> Result<u64> {
+        Ok(argument
+            .ok_or_else(|| anyhow!("Missing user id"))?
+            .parse()?)
+    };
+    let command = match (words[2].trim(), argument) {
+        ("halt", Some(symbol)) => AdminCommand::HaltSymbol(symbol.to_string()),
+        ("halt", None) => AdminCommand::HaltMarket,
+        ("resume", Some(symbol)) => AdminCommand::ResumeSymbol(symbol.to_string()),
+        ("resume", None) => AdminCommand::ResumeMarket,
+        ("kill", _) => AdminCommand::KillUser(user_id()?),
+        ("enable", _) => AdminCommand::EnableUser(user_id()?),
+        ("deposit", _) => {
+            if words.len() < 6 {
+                return Err(anyhow!("Too few fields in a deposit"));
+            }
+            AdminCommand::Deposit(Deposit {
+                user_id: user_id()?,
+                asset: words[4].trim().to_string(),
+                amount: words[5].trim().parse()?,
+            })
+        }
+        ("phase", Some(symbol)) => {
+            if words.len() < 5 {
+                return Err(anyhow!("Too few fields in a phase change"));
+            }
+            let phase = match words[4].trim() {
+                "pre-open" => Phase::PreOpen,
+                "auction" => Phase::Auction,
+                "continuous" => Phase::Continuous,
+                "halted" => Phase::Halted,
+                "closed" => Phase::Closed,
+                phase => return Err(anyhow!("Invalid phase {}", phase)),
+            };
+            AdminCommand::SetPhase(PhaseChange {
+                symbol: symbol.to_string(),
+                phase,
+            })
+        }
+        (action, _) => return Err(anyhow!("Unrecognized admin action {}", action)),
+    };
+    Ok(Command::Admin(AdminRequest { token, command }))
+}
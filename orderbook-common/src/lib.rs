@@ -1,15 +1,31 @@
 use serde::{Deserialize, Serialize};
 
+pub mod input;
+
 pub const SOCKET: &str = "/tmp/orderbook-server.sock";
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Command {
     New(NewOrder),
     Cancel(CancelOrder),
     Flush,
+    Oco(OcoOrder),
+    Bracket(BracketOrder),
+    QueryPositions(PositionQuery),
+    MassCancel(MassCancel),
+    Logon(Logon),
+    Heartbeat,
+    Admin(AdminRequest),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A new order. A zero price stands for a market order. Orders with a stop price are stop
+/// orders, which become market or limit orders once the stop price is traded, and orders with a
+/// trail are trailing stop orders, whose stop price follows the last trade price. Limit orders with
+/// a display quantity are iceberg orders. The price of a pegged order follows the book, with the
+/// order price, if any, as its limit. An order with a minimum quantity only trades on arrival if at
+/// least that much can be executed immediately, and an all-or-none order is only filled completely.
+/// A hidden order is left out of the market data.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct NewOrder {
     pub user_id: u64,
     pub user_order_id: u64,
@@ -17,10 +33,241 @@ pub struct NewOrder {
     pub price: u64,
     pub quantity: u64,
     pub side: char,
+    #[serde(default)]
+    pub stop_price: Option<u64>,
+    #[serde(default)]
+    pub display_quantity: Option<u64>,
+    #[serde(default)]
+    pub post_only: Option<PostOnly>,
+    #[serde(default)]
+    pub peg: Option<Peg>,
+    #[serde(default)]
+    pub trail: Option<Trail>,
+    #[serde(default)]
+    pub min_quantity: Option<u64>,
+    #[serde(default)]
+    pub all_or_none: bool,
+    #[serde(default)]
+    pub hidden: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What to do with a post-only order that would match immediately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostOnly {
+    /// Reject the order.
+    Reject,
+    /// Reprice the order one tick away from the best opposite price.
+    Reprice,
+}
+
+/// Reference price a pegged order follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PegReference {
+    /// The best price on the same side of the book.
+    Primary,
+    /// The best price on the opposite side of the book.
+    Market,
+    /// The midpoint between the best bid and the best ask.
+    Midpoint,
+}
+
+/// The reference price and the offset from it that a pegged order follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset: i64,
+}
+
+/// Distance of the stop price of a trailing stop order from the last trade price.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Trail {
+    /// A fixed price difference.
+    Amount(u64),
+    /// A percentage of the last trade price.
+    Percent(f64),
+}
+
+/// A one-cancels-other pair of orders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OcoOrder {
+    pub first: NewOrder,
+    pub second: NewOrder,
+    pub cancel_on: OcoCancel,
+}
+
+/// When an order of a one-cancels-other pair cancels the other one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OcoCancel {
+    /// As soon as the order is at least partially filled.
+    PartialFill,
+    /// Only once the order is completely filled.
+    Fill,
+}
+
+/// An entry order with take-profit and stop-loss orders, which become a one-cancels-other pair
+/// once the entry order is filled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BracketOrder {
+    pub entry: NewOrder,
+    pub take_profit: NewOrder,
+    pub stop_loss: NewOrder,
+    pub cancel_on: OcoCancel,
+}
+
+/// An administrative change of the trading phase of a security.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseChange {
+    pub symbol: String,
+    pub phase: Phase,
+}
+
+/// Trading phase of a security.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    PreOpen,
+    Auction,
+    Continuous,
+    Halted,
+    Closed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CancelOrder {
     pub user_id: u64,
     pub user_order_id: u64,
 }
+
+/// Cancellation of all the orders of a user, in a security, on a side, or any combination of
+/// these. Fields that aren't given match every order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MassCancel {
+    #[serde(default)]
+    pub user_id: Option<u64>,
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub side: Option<char>,
+}
+
+/// Options of the session of a connection. With cancel on disconnect, the orders entered through
+/// the session are cancelled once the connection drops. With a heartbeat, the
+/// connection is considered dropped if nothing comes from the client for that many seconds; the
+/// client sends heartbeats to keep an idle connection alive.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Logon {
+    #[serde(default)]
+    pub cancel_on_disconnect: bool,
+    #[serde(default)]
+    pub heartbeat_secs: Option<u64>,
+}
+
+/// A deposit of an asset, either cash or a security, to the balance of a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deposit {
+    pub user_id: u64,
+    pub asset: String,
+    pub amount: u64,
+}
+
+/// An operator command together with the token that authenticates the operator. Admin commands
+/// are kept apart from the trading commands, and the server only journals them, without the
+/// token, once they are authenticated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminRequest {
+    #[serde(default)]
+    pub token: String,
+    pub command: AdminCommand,
+}
+
+/// Operator commands controlling trading on the market.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Halt trading in a single security.
+    HaltSymbol(String),
+    /// Resume continuous trading in a halted security.
+    ResumeSymbol(String),
+    /// Halt trading on the whole market, whatever the phases of the securities.
+    HaltMarket,
+    /// Resume trading on the whole market.
+    ResumeMarket,
+    /// Cancel all the orders of the user and reject new ones.
+    KillUser(u64),
+    /// Accept orders of a user disabled by the kill switch again.
+    EnableUser(u64),
+    /// Credit the balance of a user with funds coming from outside the market.
+    Deposit(Deposit),
+    /// Change the trading phase of a security.
+    SetPhase(PhaseChange),
+}
+
+/// A request for the positions of a user. It doesn't change the market, so it isn't journaled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionQuery {
+    pub user_id: u64,
+}
+
+/// Position of a user in a security with its profit and loss. The unrealized profit and loss is
+/// marked to the midpoint of the book or the last trade price, and is zero if there is neither.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub symbol: String,
+    /// Net quantity, positive if long and negative if short.
+    pub quantity: i64,
+    pub average_cost: f64,
+    pub realized_pnl: f64,
+    pub mark_price: Option<f64>,
+    pub unrealized_pnl: f64,
+}
+
+/// Messages sent by the server back to connected clients.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// The server is shutting down and will not accept any more commands.
+    Shutdown,
+    /// An order or a cancellation sent by the client has been accepted. The order id is the
+    /// exchange id of the order.
+    Accepted {
+        user_id: u64,
+        user_order_id: u64,
+        order_id: u64,
+    },
+    /// A command sent by the client has led to a trade. The orders are given by their user ids
+    /// and their exchange ids, and the aggressor is the side of the order that took liquidity,
+    /// `B` or `S`, or none for auction trades. The fees are those charged to the buyer and the
+    /// seller, negative for rebates. The timestamp is the time of the trade in nanoseconds since
+    /// the Unix epoch.
+    Trade {
+        trade_id: u64,
+        symbol: String,
+        price: f64,
+        quantity: u64,
+        fee_buy: f64,
+        fee_sell: f64,
+        timestamp: u64,
+        user_id_buy: u64,
+        user_order_id_buy: u64,
+        order_id_buy: u64,
+        user_id_sell: u64,
+        user_order_id_sell: u64,
+        order_id_sell: u64,
+        aggressor: Option<char>,
+    },
+    /// An order or a cancellation sent by the client has been rejected for the given reason.
+    Rejected {
+        user_id: u64,
+        user_order_id: u64,
+        reason: String,
+    },
+    /// An admin command sent by the client has been authenticated and applied.
+    AdminAck { command: AdminCommand },
+    /// An admin command sent by the client has been refused for the given reason.
+    AdminRejected {
+        command: AdminCommand,
+        reason: String,
+    },
+    /// The positions of a user the client has asked for.
+    Positions {
+        user_id: u64,
+        positions: Vec<Position>,
+    },
+}
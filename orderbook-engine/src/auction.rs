@@ -0,0 +1,118 @@
+//! This module contains the computation of the equilibrium price of a call auction.
+
+use crate::level::Level;
+use crate::Price;
+
+use std::cmp::Reverse;
+
+/// Equilibrium is the single price at which a call auction uncrosses the book, together with the
+/// volume executed at that price and the imbalance left over. The imbalance is positive if more is
+/// bid than offered at the price, and negative otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Equilibrium {
+    pub price: Price,
+    pub volume: u64,
+    pub imbalance: i64,
+}
+
+/// Find the equilibrium price of the given bid and ask levels. Of all the level prices, it is the
+/// one with the maximum executable volume, then the one with the minimum imbalance, then the one
+/// closest to the reference price and finally the lowest one. There is no equilibrium if the
+/// book doesn't cross.
+pub(crate) fn equilibrium(
+    bids: &[&Level],
+    asks: &[&Level],
+    reference: Option<Price>,
+) -> Option<Equilibrium> {
+    let distance =
+        |price: Price| reference.map(|reference| Price::from((price.0 - reference.0).abs()));
+    let key = |equilibrium: &Equilibrium| {
+        (
+            Reverse(equilibrium.volume),
+            equilibrium.imbalance.abs(),
+            distance(equilibrium.price),
+            equilibrium.price,
+        )
+    };
+    let mut best: Option<Equilibrium> = None;
+    for price in bids.iter().chain(asks).map(|level| level.price()) {
+        let bid = volume(bids.iter().filter(|level| level.price() >= price));
+        let ask = volume(asks.iter().filter(|level| level.price() <= price));
+        let candidate = Equilibrium {
+            price,
+            volume: bid.min(ask),
+            imbalance: bid as i64 - ask as i64,
+        };
+        if candidate.volume > 0 && best.is_none_or(|best| key(&candidate) < key(&best)) {
+            best = Some(candidate);
+        }
+    }
+    best
+}
+
+/// Get the whole remaining quantity of the orders in the levels, including the hidden ones.
+fn volume<'a>(levels: impl Iterator<Item = &'a &'a Level>) -> u64 {
+    levels
+        .flat_map(|level| level.orders())
+        .map(|order| order.remaining_quantity())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Order, Side};
+    use crate::Symbol;
+    use string_interner::StringInterner;
+
+    fn level(symbol: Symbol, side: Side, price: f64, quantity: u64) -> Level {
+        let mut level = Level::new(price.into(), side);
+        level.add(Order::with_ids(1, 1).limit_order(side, symbol, price, quantity));
+        level
+    }
+
+    #[test]
+    fn test_maximum_volume_then_minimum_imbalance() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let bids = [
+            level(aapl, Side::Bid, 12.0, 10),
+            level(aapl, Side::Bid, 11.0, 5),
+        ];
+        let asks = [
+            level(aapl, Side::Ask, 10.0, 8),
+            level(aapl, Side::Ask, 11.0, 6),
+        ];
+        let equilibrium = equilibrium(
+            &bids.iter().collect::<Vec<_>>(),
+            &asks.iter().collect::<Vec<_>>(),
+            None,
+        );
+        assert_eq!(
+            equilibrium,
+            Some(Equilibrium {
+                price: 11.0.into(),
+                volume: 14,
+                imbalance: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_reference_price_breaks_ties() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let bids = [level(aapl, Side::Bid, 12.0, 5)];
+        let asks = [level(aapl, Side::Ask, 10.0, 5)];
+        let bids = bids.iter().collect::<Vec<_>>();
+        let asks = asks.iter().collect::<Vec<_>>();
+        let price = |reference: Option<f64>| {
+            equilibrium(&bids, &asks, reference.map(Price::from))
+                .map(|equilibrium| equilibrium.price)
+        };
+        assert_eq!(price(None), Some(10.0.into()));
+        assert_eq!(price(Some(13.0)), Some(12.0.into()));
+        assert_eq!(price(Some(11.0)), Some(10.0.into()));
+        assert!(equilibrium(&asks, &bids, None).is_none());
+    }
+}
@@ -1,22 +1,85 @@
 //! This module contains the definition of an order book - the primary structure for trading on the market.
 
+use crate::auction::{self, Equilibrium};
+use crate::event::RejectReason;
 use crate::level::Level;
 use crate::market::Index;
 use crate::matcher::Matcher;
-use crate::order::{Order, Side};
+use crate::order::{LimitOrder, Order, Peg, PegReference, PostOnly, Side};
+use crate::session::Phase;
 use crate::trade::Trade;
-use crate::Price;
+use crate::trigger::TriggerBook;
+use crate::{Price, TICK_SIZE};
 
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
 
 type LevelMap = BTreeMap<Price, Level>;
 
 /// Book represents an order book. It is implemented as a collection of levels for bid and for ask
 /// orders separately. When a match must be done, the top level from the bids collection and the
-/// bottom level from the asks collection are matched according to a matching algorithm.
+/// bottom level from the asks collection are matched according to a matching algorithm. Stop
+/// orders wait in a separate trigger book until the last trade price reaches their stop price.
+///
+/// Pegged orders rest in the levels like any other limit order, but the book also keeps track of
+/// where they are by their ids, so that they can be repositioned whenever a reference price
+/// changes.
+/// Pegged orders without a reference price, e.g. midpoint pegs while one side of the book is
+/// empty, are parked off the book until they get one.
+///
+/// All-or-none orders resting in the book are left out of the best bid and ask, since they can't
+/// be traded against in part. Incoming orders may rest at prices crossing them, but never at
+/// prices crossing any other order: an order that can't trade its minimum quantity on arrival is
+/// cancelled instead.
+///
+/// Orders only match in the continuous trading phase. Otherwise, e.g. during a call auction, they
+/// accumulate in the book without matching, and the book may cross. When continuous trading
+/// starts or a closing auction ends, the book is uncrossed: all the matches are executed at a
+/// single equilibrium price. If the book has a price band, an incoming order that would trade
+/// outside of it halts the book instead, and the book keeps the time it is scheduled to reopen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Book {
+    #[serde(with = "serde_levels")]
     bids: LevelMap,
+    #[serde(with = "serde_levels")]
     asks: LevelMap,
+    triggers: TriggerBook,
+    last_price: Option<Price>,
+    #[serde(with = "serde_pegs")]
+    pegs: HashMap<(u64, u64), PegEntry>,
+    // Number of the next pegged order placed at its price, which keeps their arrival order.
+    next_peg: u64,
+    // Best bid and ask reference prices the pegged orders were last priced at.
+    references: (Option<Price>, Option<Price>),
+    parked: Vec<Order>,
+    phase: Phase,
+    band: Option<f64>,
+    scheduled: Option<(Phase, Duration)>,
+}
+
+/// Location of a pegged order: its side and the price of the level it rests in, or none if it is
+/// parked, together with the number giving the order in which the pegged orders were placed at
+/// their current prices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PegEntry {
+    side: Side,
+    price: Option<Price>,
+    arrival: u64,
+}
+
+/// Execution is the outcome of adding an order to the book.
+#[derive(Debug, Default)]
+pub struct Execution {
+    /// Trades made by the incoming order, by the stop orders it triggered and by the pegged
+    /// orders it moved, in the order they were made.
+    pub trades: Vec<Trade>,
+    /// Orders removed from the book without being completely filled, e.g. the unfilled
+    /// remainders of market orders.
+    pub cancelled: Vec<Order>,
+    /// Whether an order would have traded outside of the price band, halting the book.
+    pub halted: bool,
 }
 
 impl Book {
@@ -25,16 +88,107 @@ impl Book {
         Self {
             bids: LevelMap::new(),
             asks: LevelMap::new(),
+            triggers: TriggerBook::new(),
+            last_price: None,
+            pegs: HashMap::new(),
+            next_peg: 0,
+            references: (None, None),
+            parked: Vec::new(),
+            phase: Phase::default(),
+            band: None,
+            scheduled: None,
+        }
+    }
+
+    /// Add an order to the book. Stop orders are put into the trigger book unless the last trade
+    /// price already triggers them. Every trade may trigger stop orders, which are then added to
+    /// the book one by one, possibly triggering even more of them. Finally, pegged orders whose
+    /// reference price has changed are repositioned.
+    pub fn add<M: Matcher>(&mut self, order: Order, matcher: &mut M) -> Execution {
+        self.process(VecDeque::from(vec![order]), matcher)
+    }
+
+    /// Reposition the pegged orders whose reference price has changed, e.g. after an order has
+    /// been removed from the book.
+    pub fn reprice<M: Matcher>(&mut self, matcher: &mut M) -> Execution {
+        self.process(VecDeque::new(), matcher)
+    }
+
+    fn process<M: Matcher>(&mut self, mut pending: VecDeque<Order>, matcher: &mut M) -> Execution {
+        let matching = self.phase.is_matching();
+        let mut execution = Execution::default();
+        loop {
+            while let Some(order) = pending.pop_front() {
+                self.execute(order, matcher, &mut pending, &mut execution);
+            }
+            // Reference prices only depend on orders that are not pegged, so repositioning the
+            // pegged orders settles unless it triggers some stop orders.
+            pending.extend(self.take_repriced_pegs());
+            if pending.is_empty() {
+                break;
+            }
+        }
+        // Trailing stop orders follow the price once the whole batch of trades is done.
+        if let Some(trade) = execution.trades.last() {
+            self.triggers.trail(trade.price);
         }
+        execution.halted = matching && self.phase == Phase::Halted;
+        execution
     }
 
-    /// Add an order to the book.
-    pub fn add<M: Matcher>(&mut self, mut order: Order, matcher: &mut M) -> (bool, Vec<Trade>) {
-        let trades = self.try_execute(&mut order, matcher);
-        if order.is_done() {
-            return (false, trades);
+    fn execute<M: Matcher>(
+        &mut self,
+        order: Order,
+        matcher: &mut M,
+        pending: &mut VecDeque<Order>,
+        execution: &mut Execution,
+    ) {
+        let mut order = match order {
+            Order::Stop(mut stop) => match self.last_price {
+                Some(price) if stop.is_triggered(price) && self.phase.is_matching() => {
+                    stop.trigger()
+                }
+                Some(price) => {
+                    stop.trail(price);
+                    self.triggers.add(stop);
+                    return;
+                }
+                None => {
+                    self.triggers.add(stop);
+                    return;
+                }
+            },
+            order => order,
+        };
+        if !self.price_peg(&mut order) {
+            self.park(order);
+            return;
         }
+        let trades = if self.phase.is_matching() {
+            self.try_execute(&mut order, matcher)
+        } else {
+            Vec::new()
+        };
+        for trade in &trades {
+            self.last_price = Some(trade.price);
+            pending.extend(self.triggers.take_triggered(trade.price));
+        }
+        self.forget_filled_pegs(&trades);
+        execution.trades.extend(trades);
+        match order {
+            _ if order.is_done() => (),
+            Order::Limit(_) if !self.crosses_on_rest(&order) => self.rest(order),
+            _ => execution.cancelled.push(order),
+        }
+    }
+
+    /// Put the order into its price level.
+    fn rest(&mut self, mut order: Order) {
+        order.hide_reserve();
         let price = order.price();
+        if order.peg().is_some() {
+            self.track_peg(&order, Some(price));
+        }
         let level = match order.side() {
             Side::Bid => self
                 .bids
@@ -46,64 +200,509 @@ impl Book {
                 .or_insert_with(|| Level::new(price, Side::Ask)),
         };
         level.add(order);
-        (true, trades)
+    }
+
+    /// Keep a pegged order without a reference price off the book.
+    fn park(&mut self, order: Order) {
+        self.track_peg(&order, None);
+        self.parked.push(order);
+    }
+
+    /// Keep track of a pegged order placed at the given price, or parked if there is none.
+    fn track_peg(&mut self, order: &Order, price: Option<Price>) {
+        let entry = PegEntry {
+            side: order.side(),
+            price,
+            arrival: self.next_peg,
+        };
+        self.next_peg += 1;
+        self.pegs
+            .insert((order.user_id(), order.user_order_id()), entry);
+    }
+
+    /// Forget the pegged orders the trades have filled.
+    fn forget_filled_pegs(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            for ids in [
+                (trade.user_id_buy, trade.user_order_id_buy),
+                (trade.user_id_sell, trade.user_order_id_sell),
+            ] {
+                if self.pegs.contains_key(&ids) && self.get_peg(ids).is_none() {
+                    self.pegs.remove(&ids);
+                }
+            }
+        }
+    }
+
+    /// Get the current trading phase.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Move on to the given trading phase, uncrossing the book when continuous trading starts or
+    /// a closing auction ends. Returns `None` if the current phase can't be followed by the
+    /// given one.
+    pub fn set_phase<M: Matcher>(&mut self, phase: Phase, matcher: &mut M) -> Option<Execution> {
+        if !self.phase.can_become(phase) {
+            return None;
+        }
+        self.scheduled = None;
+        let uncross = match phase {
+            Phase::Continuous => true,
+            Phase::Closed => self.phase == Phase::Auction,
+            _ => false,
+        };
+        let execution = if uncross {
+            self.uncross(matcher)
+        } else {
+            Execution::default()
+        };
+        if !execution.halted {
+            self.phase = phase;
+        }
+        Some(execution)
+    }
+
+    /// Set the width of the price band as a fraction of the reference price, or remove the band.
+    pub fn set_band(&mut self, band: Option<f64>) {
+        self.band = band;
+    }
+
+    /// Schedule a change of the trading phase at the given time.
+    pub fn schedule(&mut self, phase: Phase, at: Duration) {
+        self.scheduled = Some((phase, at));
+    }
+
+    /// Drop the scheduled change of the trading phase, if any.
+    pub fn unschedule(&mut self) {
+        self.scheduled = None;
+    }
+
+    /// Get the scheduled change of the trading phase, if any.
+    pub fn scheduled(&self) -> Option<(Phase, Duration)> {
+        self.scheduled
+    }
+
+    /// Get the price at which the call auction in progress would currently uncross, if any.
+    pub fn indicative(&self) -> Option<Equilibrium> {
+        if self.phase != Phase::Auction {
+            return None;
+        }
+        self.equilibrium()
+    }
+
+    fn equilibrium(&self) -> Option<Equilibrium> {
+        let bids = self.levels(Side::Bid).collect::<Vec<_>>();
+        let asks = self.levels(Side::Ask).collect::<Vec<_>>();
+        auction::equilibrium(&bids, &asks, self.last_price)
+    }
+
+    /// Uncross the book. All the bid orders at or above the equilibrium price are matched in
+    /// their priority order with the ask orders at or below it, and every trade is made at the
+    /// equilibrium price. The trades may then trigger stop orders and move pegged orders as if
+    /// the book was matching continuously.
+    fn uncross<M: Matcher>(&mut self, matcher: &mut M) -> Execution {
+        self.phase = Phase::Continuous;
+        let price = match self.equilibrium() {
+            Some(equilibrium) => equilibrium.price,
+            None => return self.reprice(matcher),
+        };
+        // The equilibrium price becomes the reference price of the price band right away, so
+        // that it doesn't stop the uncrossing itself.
+        self.last_price = Some(price);
+        let mut trades = Vec::new();
+        let crossing = self.bids.split_off(&price);
+        for (key, level) in crossing.into_iter().rev() {
+            let mut left = Level::new(level.price(), Side::Bid);
+            for mut order in level.orders().iter().rev().cloned() {
+                let limit = order.price();
+                order.reveal_reserve();
+                set_limit_price(&mut order, price);
+                trades.append(&mut self.try_execute(&mut order, matcher));
+                set_limit_price(&mut order, limit);
+                if !order.is_done() {
+                    order.hide_reserve();
+                    left.add(order);
+                }
+            }
+            if !left.is_empty() {
+                self.bids.insert(key, left);
+            }
+        }
+        self.forget_filled_pegs(&trades);
+        // Auction trades have no aggressor, all the orders are matched at once.
+        for trade in &mut trades {
+            trade.set_price(price);
+            trade.aggressor = None;
+        }
+        let pending = self.triggers.take_triggered(price);
+        self.triggers.trail(price);
+        let mut execution = self.process(pending.into(), matcher);
+        trades.append(&mut execution.trades);
+        execution.trades = trades;
+        execution
     }
 
     /// Cancel an given order, removing it from the order book immediately.
     pub fn remove(&mut self, index: &Index) -> Option<Order> {
-        let level = match index.side {
-            Side::Bid => self.bids.get_mut(&index.price)?,
-            Side::Ask => self.asks.get_mut(&-index.price)?,
+        let (user_id, user_order_id) = index.ids();
+        if let Some(stop) = self.triggers.remove(user_id, user_order_id) {
+            return Some(Order::Stop(stop));
+        }
+        let price = match self.pegs.remove(&(user_id, user_order_id)) {
+            Some(entry) => match entry.price {
+                Some(price) => price,
+                None => return self.unpark(user_id, user_order_id),
+            },
+            None => index.price,
+        };
+        self.remove_from_level(index.side, price, user_id, user_order_id)
+    }
+
+    fn remove_from_level(
+        &mut self,
+        side: Side,
+        price: Price,
+        user_id: u64,
+        user_order_id: u64,
+    ) -> Option<Order> {
+        let (levels, key) = match side {
+            Side::Bid => (&mut self.bids, price),
+            Side::Ask => (&mut self.asks, -price),
+        };
+        let level = levels.get_mut(&key)?;
+        let order = level.remove(user_id, user_order_id);
+        if level.is_empty() {
+            levels.remove(&key);
+        }
+        order
+    }
+
+    fn unpark(&mut self, user_id: u64, user_order_id: u64) -> Option<Order> {
+        let position = self.parked.iter().position(|order| {
+            order.user_id() == user_id && order.user_order_id() == user_order_id
+        })?;
+        Some(self.parked.remove(position))
+    }
+
+    /// Check if an order given by its index is still in the book, in the trigger book or parked.
+    pub fn contains(&self, index: &Index) -> bool {
+        let (user_id, user_order_id) = index.ids();
+        if self.triggers.get(user_id, user_order_id).is_some() {
+            return true;
+        }
+        match self.pegs.contains_key(&(user_id, user_order_id)) {
+            true => self.get_peg((user_id, user_order_id)).is_some(),
+            false => self
+                .get(index.side, index.price, user_id, user_order_id)
+                .is_some(),
+        }
+    }
+
+    fn get(&self, side: Side, price: Price, user_id: u64, user_order_id: u64) -> Option<&Order> {
+        let level = match side {
+            Side::Bid => self.bids.get(&price)?,
+            Side::Ask => self.asks.get(&-price)?,
+        };
+        level.get(user_id, user_order_id)
+    }
+
+    /// Find the pegged order given by its ids. Pegged orders that have been filled are not
+    /// found, even though their entries may not have been removed yet.
+    fn get_peg(&self, (user_id, user_order_id): (u64, u64)) -> Option<&Order> {
+        let entry = self.pegs.get(&(user_id, user_order_id))?;
+        match entry.price {
+            Some(price) => self.get(entry.side, price, user_id, user_order_id),
+            None => self
+                .parked
+                .iter()
+                .find(|order| order.user_id() == user_id && order.user_order_id() == user_order_id),
+        }
+    }
+
+    /// Set the price of a pegged order according to its reference price. Returns false if the
+    /// order is pegged, but there is no reference price.
+    fn price_peg(&self, order: &mut Order) -> bool {
+        let price = match order.peg() {
+            Some(peg) => self.peg_price(order.side(), peg),
+            None => return true,
+        };
+        match (price, order) {
+            (Some(price), Order::Limit(order)) => {
+                order.price = price;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Compute the price of a pegged order. The price never locks or crosses the best opposite
+    /// price, so pegged orders may only trade with other pegged orders when they are
+    /// repositioned, e.g. midpoint pegs with each other at the midpoint.
+    pub(crate) fn peg_price(&self, side: Side, peg: Peg) -> Option<Price> {
+        let best_bid = self.reference_price(Side::Bid);
+        let best_ask = self.reference_price(Side::Ask);
+        let reference = match (peg.reference, side) {
+            (PegReference::Primary, Side::Bid) | (PegReference::Market, Side::Ask) => best_bid?,
+            (PegReference::Primary, Side::Ask) | (PegReference::Market, Side::Bid) => best_ask?,
+            (PegReference::Midpoint, _) => (best_bid? + best_ask?) / 2.0,
+        };
+        let mut price = reference + peg.offset;
+        match side {
+            Side::Bid => {
+                if let Some(best_ask) = best_ask.filter(|&best_ask| price >= best_ask) {
+                    price = best_ask - TICK_SIZE;
+                }
+                price = peg.limit.map_or(price, |limit| price.min(limit));
+            }
+            Side::Ask => {
+                if let Some(best_bid) = best_bid.filter(|&best_bid| price <= best_bid) {
+                    price = best_bid + TICK_SIZE;
+                }
+                price = peg.limit.map_or(price, |limit| price.max(limit));
+            }
+        }
+        Some(price).filter(|&price| price > 0.0.into())
+    }
+
+    /// Get the best price on the given side among the displayed orders that are neither pegged
+    /// nor all-or-none.
+    fn reference_price(&self, side: Side) -> Option<Price> {
+        self.levels(side)
+            .find(|level| {
+                level.orders().iter().any(|order| {
+                    order.peg().is_none() && !order.is_hidden() && !order.is_all_or_none()
+                })
+            })
+            .map(|level| level.price())
+    }
+
+    /// Take the pegged orders whose price has to change out of the book, together with the
+    /// parked ones that got a reference price, in their arrival order. Prices of pegged orders
+    /// only depend on the reference prices, so nothing changes unless one of them has.
+    fn take_repriced_pegs(&mut self) -> Vec<Order> {
+        let references = (
+            self.reference_price(Side::Bid),
+            self.reference_price(Side::Ask),
+        );
+        if references == self.references {
+            return Vec::new();
+        }
+        self.references = references;
+        let mut moved = Vec::new();
+        let mut filled = Vec::new();
+        for (&ids, entry) in &self.pegs {
+            let peg = match self.get_peg(ids) {
+                Some(order) => order.peg().unwrap(),
+                None => {
+                    filled.push(ids);
+                    continue;
+                }
+            };
+            if self.peg_price(entry.side, peg) != entry.price {
+                moved.push((entry.arrival, ids));
+            }
+        }
+        for ids in filled {
+            self.pegs.remove(&ids);
+        }
+        moved.sort_unstable();
+        let mut repriced = Vec::new();
+        for (_, (user_id, user_order_id)) in moved {
+            let entry = self.pegs.remove(&(user_id, user_order_id)).unwrap();
+            let order = match entry.price {
+                Some(price) => self.remove_from_level(entry.side, price, user_id, user_order_id),
+                None => self.unpark(user_id, user_order_id),
+            };
+            repriced.extend(order);
+        }
+        repriced
+    }
+
+    /// Check if the rest of an incoming order would cross the book if it was put into its level,
+    /// which happens to orders that couldn't trade their minimum quantity. All-or-none orders may
+    /// rest anywhere, as they are left out of the best prices themselves.
+    fn crosses_on_rest(&self, order: &Order) -> bool {
+        self.phase.is_matching() && !order.is_all_or_none() && self.crosses(order)
+    }
+
+    /// Check if the order would be matched immediately if it was added to the book.
+    pub fn crosses(&self, order: &Order) -> bool {
+        if let Order::Stop(_) = order {
+            return false;
+        }
+        match (order.side(), self.best_price(order.side().opposite())) {
+            (Side::Bid, Some(price)) => order.price() >= price,
+            (Side::Ask, Some(price)) => order.price() <= price,
+            (_, None) => false,
+        }
+    }
+
+    /// Make sure that a post-only order doesn't match immediately, either by rejecting it or by
+    /// repricing it one tick away from the best opposite price. Other orders are left as they are,
+    /// except that pegged orders get their current price.
+    pub fn post(&self, order: &mut Order) -> Result<(), RejectReason> {
+        self.price_peg(order);
+        let post_only = match order {
+            Order::Limit(LimitOrder {
+                post_only: Some(post_only),
+                ..
+            }) => *post_only,
+            _ => return Ok(()),
+        };
+        let best = match self.best_price(order.side().opposite()) {
+            Some(best) if self.crosses(order) => best,
+            _ => return Ok(()),
         };
-        level.remove(index.user_id, index.user_order_id)
+        let price = match order.side() {
+            Side::Bid => best - TICK_SIZE,
+            Side::Ask => best + TICK_SIZE,
+        };
+        match (post_only, order) {
+            (PostOnly::Reprice, Order::Limit(order)) if price > 0.0.into() => {
+                order.price = price;
+                Ok(())
+            }
+            _ => Err(RejectReason::WouldTakeLiquidity),
+        }
     }
 
-    /// Try executing the order.
+    /// Try executing the order. Nothing is executed if less than the minimum quantity of the
+    /// order can be executed. If the order reaches a level outside of the price band, matching
+    /// stops and the book is halted.
     fn try_execute<M: Matcher>(&mut self, order: &mut Order, matcher: &mut M) -> Vec<Trade> {
+        let min_quantity = order.min_quantity();
+        if min_quantity > 0 && min_quantity > self.executable_quantity(order) {
+            return Vec::new();
+        }
+        let band_limit = self.band_limit(order.side());
+        let mut halted = false;
         let levels = match order.side() {
             Side::Bid => &mut self.asks,
             Side::Ask => &mut self.bids,
         };
         let mut trades = Vec::new();
-        while !levels.is_empty() {
-            let (&key, top_level) = levels.iter_mut().next_back().unwrap();
+        // Levels may keep orders the matcher skipped, so the next level is the one below the
+        // last one visited rather than the top one.
+        let mut visited = None;
+        while !order.is_done() {
+            let next_level = match visited {
+                None => levels.iter_mut().next_back(),
+                Some(key) => levels.range_mut(..key).next_back(),
+            };
+            let (&key, level) = match next_level {
+                Some(next_level) => next_level,
+                None => break,
+            };
             match order.side() {
-                Side::Bid if order.price() < top_level.price() => break,
-                Side::Ask if order.price() > top_level.price() => break,
+                Side::Bid if order.price() < level.price() => break,
+                Side::Ask if order.price() > level.price() => break,
                 _ => (),
             }
-            trades.append(&mut matcher.match_order(order, top_level));
-            if top_level.is_empty() {
-                levels.remove(&key);
-            }
-            if order.is_done() {
+            halted = match (order.side(), band_limit) {
+                (Side::Bid, Some(limit)) => level.price() > limit,
+                (Side::Ask, Some(limit)) => level.price() < limit,
+                (_, None) => false,
+            };
+            if halted {
                 break;
             }
+            trades.append(&mut matcher.match_order(order, level));
+            if level.is_empty() {
+                levels.remove(&key);
+            }
+            visited = Some(key);
+        }
+        if halted {
+            self.phase = Phase::Halted;
         }
         trades
     }
 
-    /// Clear this order book of all orders.
+    /// Get the furthest price an incoming order on the given side may trade at according to the
+    /// price band around the last trade price, if any.
+    fn band_limit(&self, side: Side) -> Option<Price> {
+        let (band, reference) = (self.band?, self.last_price?);
+        Some(match side {
+            Side::Bid => Price::from(reference.0 * (1.0 + band)),
+            Side::Ask => Price::from(reference.0 * (1.0 - band)),
+        })
+    }
+
+    /// Compute how much of the order could be executed immediately, taking into account that
+    /// all-or-none orders are only matched if they can be filled completely.
+    fn executable_quantity(&self, order: &Order) -> u64 {
+        let mut remaining = order.quantity();
+        for level in self.levels(order.side().opposite()) {
+            match order.side() {
+                Side::Bid if order.price() < level.price() => break,
+                Side::Ask if order.price() > level.price() => break,
+                _ => (),
+            }
+            for other in level.orders().iter().rev() {
+                let quantity = other.remaining_quantity();
+                if other.is_all_or_none() && quantity > remaining {
+                    continue;
+                }
+                remaining -= quantity.min(remaining);
+                if remaining == 0 {
+                    return order.quantity();
+                }
+            }
+        }
+        order.quantity() - remaining
+    }
+
+    /// Clear this order book of all orders, including the stop and parked orders, and forget the last
+    /// trade price. The trading phase stays as it is.
     pub fn clear(&mut self) {
         self.bids.clear();
         self.asks.clear();
+        self.triggers.clear();
+        self.last_price = None;
+        self.pegs.clear();
+        self.references = (None, None);
+        self.parked.clear();
     }
 
-    pub fn top_of_book(&self, side: Side) -> (Price, u64) {
-        let order = match side {
-            Side::Bid => self
-                .bids
-                .iter()
-                .next_back()
-                .and_then(|(_, level)| level.top()),
-            Side::Ask => self
-                .asks
-                .iter()
-                .next_back()
-                .and_then(|(_, level)| level.top()),
+    /// Iterate over the levels on the given side, starting from the top of the book.
+    pub fn levels(&self, side: Side) -> impl Iterator<Item = &Level> {
+        match side {
+            Side::Bid => self.bids.values().rev(),
+            Side::Ask => self.asks.values().rev(),
         }
-        .expect("Order book is empty");
-        (order.price(), order.quantity())
+    }
+
+    /// Get the price and the total displayed quantity of the best level with displayed orders on
+    /// the given side, if any.
+    pub fn top_of_book(&self, side: Side) -> Option<(Price, u64)> {
+        self.levels(side)
+            .map(|level| (level.price(), level.quantity()))
+            .find(|&(_, quantity)| quantity > 0)
+    }
+
+    /// Get the price positions in the security are marked to: the midpoint between the best
+    /// displayed bid and ask, or the last trade price if either side is empty.
+    pub fn mark_price(&self) -> Option<Price> {
+        match (self.top_of_book(Side::Bid), self.top_of_book(Side::Ask)) {
+            (Some((bid, _)), Some((ask, _))) => Some((bid + ask) / 2.0),
+            _ => self.last_price,
+        }
+    }
+
+    /// Get the best price on the given side, including the hidden orders, but not the
+    /// all-or-none ones.
+    fn best_price(&self, side: Side) -> Option<Price> {
+        self.levels(side)
+            .find(|level| level.orders().iter().any(|order| !order.is_all_or_none()))
+            .map(|level| level.price())
+    }
+}
+
+/// Set the price of a limit order.
+fn set_limit_price(order: &mut Order, price: Price) {
+    if let Order::Limit(order) = order {
+        order.price = price;
     }
 }
 
@@ -113,11 +712,59 @@ impl Default for Book {
     }
 }
 
+/// Level maps are serialized as sequences of levels, since their keys are derived from the level
+/// prices anyway.
+mod serde_levels {
+    use super::LevelMap;
+    use crate::level::Level;
+    use crate::order::Side;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(levels: &LevelMap, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(levels.values())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LevelMap, D::Error> {
+        let levels = Vec::<Level>::deserialize(deserializer)?;
+        Ok(levels
+            .into_iter()
+            .map(|level| match level.side() {
+                Side::Bid => (level.price(), level),
+                Side::Ask => (-level.price(), level),
+            })
+            .collect())
+    }
+}
+
+mod serde_pegs {
+    use super::PegEntry;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(
+        pegs: &HashMap<(u64, u64), PegEntry>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        // In their arrival order, so that the same book is always written the same way.
+        let mut pegs = pegs.iter().collect::<Vec<_>>();
+        pegs.sort_by_key(|(_, entry)| entry.arrival);
+        serializer.collect_seq(pegs)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<(u64, u64), PegEntry>, D::Error> {
+        Ok(Vec::<((u64, u64), PegEntry)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::matcher::FIFOMatcher;
-    use crate::order::{Order, Side};
+    use crate::order::{Order, Side, Trail};
     use string_interner::StringInterner;
 
     fn book_from_orders(orders: impl IntoIterator<Item = Order>) -> Book {
@@ -138,7 +785,7 @@ mod tests {
         ];
         let mut book = book_from_orders(ask_orders);
         let order = Order::with_ids(2, 52).limit_order(Side::Bid, aapl, 2.0, 7);
-        let trades = book.add(order, &mut FIFOMatcher).1;
+        let trades = book.add(order, &mut FIFOMatcher).trades;
 
         // Trades are correct
         assert_eq!(trades.len(), 2);
@@ -171,7 +818,7 @@ mod tests {
         ];
         let mut book = book_from_orders(bid_orders);
         let order = Order::with_ids(2, 52).limit_order(Side::Ask, aapl, 1.0, 7);
-        let trades = book.add(order, &mut FIFOMatcher).1;
+        let trades = book.add(order, &mut FIFOMatcher).trades;
 
         // Trades are correct
         assert_eq!(trades.len(), 2);
@@ -193,4 +840,277 @@ mod tests {
         assert_eq!(book.bids.len(), 1);
         assert_eq!(book.bids.iter().next().unwrap().1.orders()[0].quantity(), 3);
     }
+
+    #[test]
+    fn test_stop_order_waits_for_trigger() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = book_from_orders([
+            Order::with_ids(10, 110).limit_order(Side::Ask, aapl, 5.0, 5),
+            Order::with_ids(11, 111).limit_order(Side::Ask, aapl, 6.0, 5),
+            Order::with_ids(12, 112).stop_order(Side::Bid, aapl, 5.0, 3),
+        ]);
+        assert_eq!(book.asks.len(), 2);
+        assert_eq!(book.bids.len(), 0);
+
+        let order = Order::with_ids(2, 52).limit_order(Side::Bid, aapl, 5.0, 4);
+        let trades = book.add(order, &mut FIFOMatcher).trades;
+        assert_eq!(trades.len(), 3);
+        assert_eq!(trades[0].user_order_id_buy, 52);
+        assert_eq!(trades[0].quantity, 4);
+        // The triggered stop order buys the rest of the first level and a part of the second.
+        assert_eq!(trades[1].user_order_id_buy, 112);
+        assert_eq!(trades[1].price, 5.0);
+        assert_eq!(trades[1].quantity, 1);
+        assert_eq!(trades[2].user_order_id_buy, 112);
+        assert_eq!(trades[2].price, 6.0);
+        assert_eq!(trades[2].quantity, 2);
+        assert_eq!(book.top_of_book(Side::Ask), Some((6.0.into(), 3)));
+    }
+
+    #[test]
+    fn test_cascading_stop_orders() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = book_from_orders([
+            Order::with_ids(10, 110).limit_order(Side::Bid, aapl, 10.0, 1),
+            Order::with_ids(11, 111).limit_order(Side::Bid, aapl, 9.0, 1),
+            Order::with_ids(12, 112).limit_order(Side::Bid, aapl, 8.0, 1),
+            // Triggered by the trade at 9, its own trade at 8 then triggers the next stop.
+            Order::with_ids(20, 120).stop_order(Side::Ask, aapl, 9.0, 1),
+            Order::with_ids(21, 121).stop_limit_order(Side::Ask, aapl, 8.5, 7.0, 5),
+        ]);
+
+        let order = Order::with_ids(2, 52).market_order(Side::Ask, aapl, 2);
+        let execution = book.add(order, &mut FIFOMatcher);
+        let sellers = execution
+            .trades
+            .iter()
+            .map(|trade| (trade.user_order_id_sell, trade.price.0))
+            .collect::<Vec<_>>();
+        assert_eq!(sellers, vec![(52, 10.0), (52, 9.0), (120, 8.0)]);
+        assert!(execution.cancelled.is_empty());
+
+        // The stop-limit order found no bids left, so it rests in the book at its limit price.
+        assert_eq!(book.bids.len(), 0);
+        assert_eq!(book.top_of_book(Side::Ask), Some((7.0.into(), 5)));
+    }
+
+    #[test]
+    fn test_market_order_remainder_is_cancelled() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book =
+            book_from_orders([Order::with_ids(10, 110).limit_order(Side::Ask, aapl, 5.0, 5)]);
+        let order = Order::with_ids(2, 52).market_order(Side::Bid, aapl, 8);
+        let execution = book.add(order, &mut FIFOMatcher);
+        assert_eq!(execution.trades.len(), 1);
+        assert_eq!(execution.trades[0].price, 5.0);
+        assert_eq!(execution.cancelled.len(), 1);
+        assert_eq!(execution.cancelled[0].quantity(), 3);
+        assert!(book.asks.is_empty() && book.bids.is_empty());
+    }
+
+    #[test]
+    fn test_trailing_stop_follows_trades() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = book_from_orders([
+            Order::with_ids(10, 110).limit_order(Side::Bid, aapl, 10.0, 1),
+            Order::with_ids(11, 111).limit_order(Side::Bid, aapl, 9.0, 5),
+            Order::with_ids(12, 112).limit_order(Side::Ask, aapl, 11.0, 1),
+            Order::with_ids(13, 113).limit_order(Side::Ask, aapl, 12.0, 1),
+            Order::with_ids(2, 51).market_order(Side::Ask, aapl, 1),
+        ]);
+        let trail = Trail::Amount(2.0.into());
+        let order = Order::with_ids(3, 60).trailing_stop_order(Side::Ask, aapl, trail, 3);
+        assert!(book.add(order, &mut FIFOMatcher).trades.is_empty());
+
+        // The stop price rises with the price from 8 to 10, but doesn't fall back with it
+        for (user_order_id, price) in [(52, 11.0), (53, 12.0)] {
+            let order = Order::with_ids(2, user_order_id).market_order(Side::Bid, aapl, 1);
+            let trades = book.add(order, &mut FIFOMatcher).trades;
+            assert_eq!(trades.len(), 1);
+            assert_eq!(trades[0].price, price);
+        }
+        let order = Order::with_ids(2, 54).limit_order(Side::Ask, aapl, 11.0, 1);
+        assert!(book.add(order, &mut FIFOMatcher).trades.is_empty());
+
+        let order = Order::with_ids(2, 55).limit_order(Side::Ask, aapl, 9.0, 1);
+        let sellers = book
+            .add(order, &mut FIFOMatcher)
+            .trades
+            .iter()
+            .map(|trade| (trade.user_order_id_sell, trade.price.0, trade.quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(sellers, vec![(55, 9.0, 1), (60, 9.0, 3)]);
+    }
+
+    #[test]
+    fn test_min_quantity() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = book_from_orders([
+            Order::with_ids(10, 110).limit_order(Side::Ask, aapl, 1.0, 5),
+            Order::with_ids(11, 111).limit_order(Side::Ask, aapl, 2.0, 5),
+            Order::with_ids(12, 112).limit_order(Side::Ask, aapl, 3.0, 5),
+        ]);
+
+        // Only 10 can be executed up to the price of 2, so the order doesn't trade, and it would
+        // cross the book if it rested.
+        let order = Order::with_ids(2, 52)
+            .min_quantity(12)
+            .limit_order(Side::Bid, aapl, 2.0, 15);
+        let execution = book.add(order, &mut FIFOMatcher);
+        assert!(execution.trades.is_empty());
+        assert_eq!(execution.cancelled.len(), 1);
+        assert_eq!(book.top_of_book(Side::Bid), None);
+
+        // An order that doesn't cross the book rests as usual.
+        let order = Order::with_ids(2, 55)
+            .min_quantity(12)
+            .limit_order(Side::Bid, aapl, 0.5, 15);
+        let execution = book.add(order, &mut FIFOMatcher);
+        assert!(execution.cancelled.is_empty());
+        assert_eq!(book.top_of_book(Side::Bid), Some((0.5.into(), 15)));
+
+        let order = Order::with_ids(2, 53)
+            .min_quantity(16)
+            .market_order(Side::Bid, aapl, 20);
+        let execution = book.add(order, &mut FIFOMatcher);
+        assert!(execution.trades.is_empty());
+        assert_eq!(execution.cancelled.len(), 1);
+
+        let order = Order::with_ids(2, 54)
+            .min_quantity(12)
+            .limit_order(Side::Bid, aapl, 3.0, 12);
+        let trades = book.add(order, &mut FIFOMatcher).trades;
+        assert_eq!(trades.len(), 3);
+        assert_eq!(book.top_of_book(Side::Ask), Some((3.0.into(), 3)));
+    }
+
+    #[test]
+    fn test_all_or_none_order_is_skipped_at_every_level() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = book_from_orders([
+            Order::with_ids(10, 110)
+                .all_or_none()
+                .limit_order(Side::Ask, aapl, 1.0, 10),
+            Order::with_ids(11, 111).limit_order(Side::Ask, aapl, 2.0, 5),
+            Order::with_ids(12, 112).limit_order(Side::Ask, aapl, 3.0, 5),
+        ]);
+        // The all-or-none order is left out of the best ask.
+        assert_eq!(book.best_price(Side::Ask), Some(2.0.into()));
+        assert_eq!(book.top_of_book(Side::Ask), Some((2.0.into(), 5)));
+
+        let order = Order::with_ids(2, 52).limit_order(Side::Bid, aapl, 2.0, 8);
+        let trades = book.add(order, &mut FIFOMatcher).trades;
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].user_order_id_sell, 111);
+        assert_eq!(trades[0].quantity, 5);
+
+        // The rest of the bid rests above the all-or-none order it can't fill, but the book
+        // isn't crossed.
+        assert_eq!(book.top_of_book(Side::Bid), Some((2.0.into(), 3)));
+        assert_eq!(book.top_of_book(Side::Ask), Some((3.0.into(), 5)));
+        assert!(book.best_price(Side::Bid) < book.best_price(Side::Ask));
+
+        // An incoming all-or-none order only trades if it can be filled completely.
+        let order = Order::with_ids(3, 53)
+            .all_or_none()
+            .limit_order(Side::Ask, aapl, 2.0, 4);
+        assert!(book.add(order, &mut FIFOMatcher).trades.is_empty());
+        assert_eq!(book.top_of_book(Side::Ask), Some((3.0.into(), 5)));
+        assert!(book.best_price(Side::Bid) < book.best_price(Side::Ask));
+    }
+
+    #[test]
+    fn test_auction_uncrosses_at_single_price() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = Book::new();
+        book.set_phase(Phase::Auction, &mut FIFOMatcher);
+        for order in [
+            Order::with_ids(1, 101).limit_order(Side::Bid, aapl, 12.0, 10),
+            Order::with_ids(2, 102).limit_order(Side::Bid, aapl, 11.0, 5),
+            Order::with_ids(3, 103).limit_order(Side::Ask, aapl, 10.0, 8),
+            Order::with_ids(4, 104).limit_order(Side::Ask, aapl, 11.0, 6),
+        ] {
+            assert!(book.add(order, &mut FIFOMatcher).trades.is_empty());
+        }
+        assert_eq!(
+            book.indicative(),
+            Some(Equilibrium {
+                price: 11.0.into(),
+                volume: 14,
+                imbalance: 1
+            })
+        );
+
+        let trades = book
+            .set_phase(Phase::Continuous, &mut FIFOMatcher)
+            .unwrap()
+            .trades;
+        assert_eq!(
+            trades
+                .iter()
+                .map(|trade| (trade.user_id_buy, trade.user_id_sell, trade.quantity))
+                .collect::<Vec<_>>(),
+            vec![(1, 3, 8), (1, 4, 2), (2, 4, 4)]
+        );
+        assert!(trades.iter().all(|trade| trade.price == Price::from(11.0)));
+        assert_eq!(book.top_of_book(Side::Bid), Some((11.0.into(), 1)));
+        assert_eq!(book.top_of_book(Side::Ask), None);
+    }
+
+    #[test]
+    fn test_pegs_move_only_with_reference_prices() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut book = book_from_orders([
+            Order::with_ids(1, 1).limit_order(Side::Bid, aapl, 10.0, 100),
+            Order::with_ids(1, 2).limit_order(Side::Ask, aapl, 13.0, 100),
+        ]);
+        let peg = Peg {
+            reference: PegReference::Primary,
+            offset: 0.0.into(),
+            limit: None,
+        };
+        book.add(
+            Order::with_ids(2, 1).pegged_order(Side::Bid, aapl, peg, 10),
+            &mut FIFOMatcher,
+        );
+        let placed = book.pegs[&(2, 1)].clone();
+        assert_eq!(placed.price, Some(10.0.into()));
+
+        // Orders that don't change the best prices leave the pegged order where it is.
+        book.add(
+            Order::with_ids(1, 3).limit_order(Side::Bid, aapl, 9.0, 100),
+            &mut FIFOMatcher,
+        );
+        book.add(
+            Order::with_ids(1, 4).limit_order(Side::Ask, aapl, 14.0, 100),
+            &mut FIFOMatcher,
+        );
+        assert_eq!(book.pegs[&(2, 1)], placed);
+
+        book.add(
+            Order::with_ids(1, 5).limit_order(Side::Bid, aapl, 11.0, 100),
+            &mut FIFOMatcher,
+        );
+        assert_eq!(book.pegs[&(2, 1)].price, Some(11.0.into()));
+        assert!(book.pegs[&(2, 1)].arrival > placed.arrival);
+
+        // The pegged order is forgotten once it is filled.
+        let trades = book
+            .add(
+                Order::with_ids(3, 1).limit_order(Side::Ask, aapl, 11.0, 110),
+                &mut FIFOMatcher,
+            )
+            .trades;
+        assert_eq!(trades.len(), 2);
+        assert!(book.pegs.is_empty());
+        assert_eq!(book.best_price(Side::Bid), Some(10.0.into()));
+    }
 }
@@ -0,0 +1,184 @@
+//! This module contains the definition of market events published by the engine.
+
+use crate::auction::Equilibrium;
+use crate::order::Side;
+use crate::session::Phase;
+use crate::trade::Trade;
+use crate::Price;
+
+/// Event represents something observable that happened on the market as a result of a command.
+/// The `Display` implementation produces the published output format. The alternate format
+/// `{:#}` adds the exchange ids: the order id to accepted orders and cancellations, and the fees,
+/// the trade id, the order ids and the aggressor side, or `-` for none, to trades.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// An order or its cancellation has been accepted by the market. The order id is the
+    /// exchange id of the order.
+    Accepted {
+        user_id: u64,
+        user_order_id: u64,
+        order_id: u64,
+    },
+    /// An order or its cancellation has been rejected by the market.
+    Rejected {
+        user_id: u64,
+        user_order_id: u64,
+        reason: RejectReason,
+    },
+    /// The top of the book has changed on the given side. The level is the price and the total
+    /// quantity of the best price level, or `None` if the side has been eliminated.
+    TopOfBook {
+        side: Side,
+        level: Option<(Price, u64)>,
+    },
+    /// The indicative price of a call auction has changed. It is the equilibrium price, the
+    /// volume and the imbalance at that price, or `None` if the book doesn't cross.
+    Indicative(Option<Equilibrium>),
+    /// The trading phase of an instrument has changed.
+    Phase { symbol: String, phase: Phase },
+    /// Trading on the whole market has been halted or resumed by an operator.
+    MarketHalt { halted: bool },
+    /// Two orders have been matched.
+    Trade(Trade),
+    /// An order has been cancelled by the market itself, e.g. the unfilled remainder of a market
+    /// order.
+    Cancelled { user_id: u64, user_order_id: u64 },
+}
+
+/// An event numbered in the global sequence of the market and stamped with the time it was
+/// published, in nanoseconds since the Unix epoch. It is displayed as the bare event.
+#[derive(Debug, Clone)]
+pub struct SequencedEvent {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+impl std::fmt::Display for SequencedEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.event.fmt(f)
+    }
+}
+
+/// Reason of rejecting an order or a cancellation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The order would cross the book while trading is turned off.
+    WouldCross,
+    /// There is no such order on the market.
+    UnknownOrder,
+    /// A post-only order would take liquidity from the book.
+    WouldTakeLiquidity,
+    /// The order or the cancellation is not allowed in the current trading phase.
+    NotAllowed(Phase),
+    /// The order quantity exceeds the limit of the user.
+    QuantityLimit,
+    /// The order value exceeds the limit of the user.
+    NotionalLimit,
+    /// The order price is too far from the top of the book.
+    PriceDeviation,
+    /// The user already has as many orders on the market as allowed.
+    OpenOrderLimit,
+    /// The order could bring the position of the user beyond the limit.
+    PositionLimit,
+    /// The user doesn't have enough funds available to back the order.
+    InsufficientFunds,
+    /// The cost of the order can't be known in advance, so it can't be backed by funds.
+    UnknownCost,
+    /// Trading on the whole market has been halted.
+    MarketHalted,
+    /// The user has been disabled by the kill switch.
+    UserDisabled,
+    /// The user has already used the order id.
+    DuplicateOrderId,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectReason::WouldCross => write!(f, "order would cross the book"),
+            RejectReason::UnknownOrder => write!(f, "unknown order"),
+            RejectReason::WouldTakeLiquidity => write!(f, "post-only order would take liquidity"),
+            RejectReason::NotAllowed(phase) => write!(f, "not allowed while {}", phase),
+            RejectReason::QuantityLimit => write!(f, "order quantity exceeds the limit"),
+            RejectReason::NotionalLimit => write!(f, "order value exceeds the limit"),
+            RejectReason::PriceDeviation => write!(f, "order price too far from the market"),
+            RejectReason::OpenOrderLimit => write!(f, "too many open orders"),
+            RejectReason::PositionLimit => write!(f, "position would exceed the limit"),
+            RejectReason::InsufficientFunds => write!(f, "insufficient funds"),
+            RejectReason::UnknownCost => write!(f, "order cost unknown in advance"),
+            RejectReason::MarketHalted => write!(f, "market halted"),
+            RejectReason::UserDisabled => write!(f, "user disabled"),
+            RejectReason::DuplicateOrderId => write!(f, "duplicate order id"),
+        }
+    }
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Event::Accepted {
+                user_id,
+                user_order_id,
+                order_id,
+            } => {
+                write!(f, "A, {}, {}", user_id, user_order_id)?;
+                if f.alternate() {
+                    write!(f, ", {}", order_id)?;
+                }
+                Ok(())
+            }
+            Event::Rejected {
+                user_id,
+                user_order_id,
+                ..
+            } => write!(f, "R, {}, {}", user_id, user_order_id),
+            Event::TopOfBook {
+                side,
+                level: Some((price, quantity)),
+            } => write!(f, "B, {}, {}, {}", side, price, quantity),
+            Event::TopOfBook { side, level: None } => write!(f, "B, {}, -, -", side),
+            Event::Indicative(Some(equilibrium)) => write!(
+                f,
+                "I, {}, {}, {}",
+                equilibrium.price, equilibrium.volume, equilibrium.imbalance
+            ),
+            Event::Indicative(None) => write!(f, "I, -, -, -"),
+            Event::Phase { symbol, phase } => write!(f, "P, {}, {}", symbol, phase),
+            Event::MarketHalt { halted: true } => write!(f, "H, halted"),
+            Event::MarketHalt { halted: false } => write!(f, "H, resumed"),
+            Event::Trade(trade) => {
+                write!(
+                    f,
+                    "T, {}, {}, {}, {}, {}, {}",
+                    trade.user_id_buy,
+                    trade.user_order_id_buy,
+                    trade.user_id_sell,
+                    trade.user_order_id_sell,
+                    trade.price,
+                    trade.quantity
+                )?;
+                // Fees are only reported when the market charges any, unless the ids follow.
+                if trade.has_fees() || f.alternate() {
+                    write!(f, ", {}, {}", trade.fee_buy, trade.fee_sell)?;
+                }
+                if f.alternate() {
+                    write!(
+                        f,
+                        ", {}, {}, {}, ",
+                        trade.trade_id, trade.order_id_buy, trade.order_id_sell
+                    )?;
+                    match trade.aggressor {
+                        Some(side) => write!(f, "{}", side)?,
+                        None => write!(f, "-")?,
+                    }
+                }
+                Ok(())
+            }
+            Event::Cancelled {
+                user_id,
+                user_order_id,
+            } => write!(f, "C, {}, {}", user_id, user_order_id),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! This module contains the fee schedule of the market and the fee totals of the users that are
+//! billed for their trading.
+
+use crate::trade::Trade;
+use crate::Symbol;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+/// Fee rates of an order as fractions of the trade value. The maker rate applies when the order
+/// provides the liquidity resting in the book, and the taker rate when the order takes it. A
+/// negative rate is a rebate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeeRates {
+    pub maker: f64,
+    pub taker: f64,
+}
+
+/// FeeSchedule keeps the fee rates per user tier, either for a single security or for all the
+/// securities, and the tiers of the users. Users without a tier of their own are in tier zero,
+/// and the default rates apply where the schedule has no rates for the tier.
+#[derive(Debug, Default)]
+pub(crate) struct FeeSchedule {
+    default: FeeRates,
+    tiers: HashMap<u64, u32>,
+    rates: HashMap<(Option<Symbol>, u32), FeeRates>,
+}
+
+impl FeeSchedule {
+    pub fn set_default_rates(&mut self, rates: FeeRates) {
+        self.default = rates;
+    }
+
+    pub fn set_rates(&mut self, symbol: Option<Symbol>, tier: u32, rates: FeeRates) {
+        self.rates.insert((symbol, tier), rates);
+    }
+
+    pub fn set_tier(&mut self, user_id: u64, tier: u32) {
+        self.tiers.insert(user_id, tier);
+    }
+
+    /// Get the rates of the user in the security, preferring the rates of the security to the
+    /// rates of all the securities.
+    pub fn rates(&self, user_id: u64, symbol: Symbol) -> FeeRates {
+        let tier = self.tiers.get(&user_id).copied().unwrap_or(0);
+        self.rates
+            .get(&(Some(symbol), tier))
+            .or_else(|| self.rates.get(&(None, tier)))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Trading of a user in a security over the billing period: the traded quantity and value, the
+/// fees charged and the rebates paid, the latter as a positive amount.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeTotal {
+    pub quantity: u64,
+    pub value: f64,
+    pub fees: f64,
+    pub rebates: f64,
+}
+
+impl FeeTotal {
+    fn add(&mut self, trade: &Trade, fee: f64) {
+        self.quantity += trade.quantity;
+        self.value += trade.price.0 * trade.quantity as f64;
+        if fee > 0.0 {
+            self.fees += fee;
+        } else {
+            self.rebates -= fee;
+        }
+    }
+}
+
+/// Billing adds up the trades of every user in every security for the billing export.
+#[derive(Debug, Default)]
+pub(crate) struct Billing {
+    totals: HashMap<(u64, Symbol), FeeTotal>,
+}
+
+impl Billing {
+    /// Add the trade to the totals of both of its users.
+    pub fn record(&mut self, trade: &Trade) {
+        self.totals
+            .entry((trade.user_id_buy, trade.symbol))
+            .or_default()
+            .add(trade, trade.fee_buy);
+        self.totals
+            .entry((trade.user_id_sell, trade.symbol))
+            .or_default()
+            .add(trade, trade.fee_sell);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((u64, Symbol), FeeTotal)> + '_ {
+        self.totals.iter().map(|(&key, &total)| (key, total))
+    }
+
+    pub fn restore(&mut self, totals: impl IntoIterator<Item = ((u64, Symbol), FeeTotal)>) {
+        self.totals = totals.into_iter().collect();
+    }
+}
@@ -0,0 +1,37 @@
+//! This module contains the definition of order groups, which link the fate of several orders
+//! together: one-cancels-other pairs and bracket orders.
+
+use crate::order::Order;
+
+use serde::{Deserialize, Serialize};
+
+/// When an order of a one-cancels-other pair cancels the other one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OcoCancel {
+    /// As soon as the order is at least partially filled.
+    PartialFill,
+    /// Only once the order is completely filled.
+    Fill,
+}
+
+/// Link is what an order in a group is linked to. The market keeps the links by the ids of the
+/// orders they belong to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Link {
+    /// The order is one of a one-cancels-other pair.
+    Oco {
+        other: (u64, u64),
+        cancel_on: OcoCancel,
+    },
+    /// The order is the first of a one-cancels-other pair that is being added to the market. The
+    /// other order is added only after it, unless the first order cancels it right away.
+    PendingOco { other: Order, cancel_on: OcoCancel },
+    /// The order is the entry of a bracket order. Once it is completely filled, the take-profit
+    /// and stop-loss children are added to the market as a one-cancels-other pair, which cancel
+    /// each other as `cancel_on` tells.
+    Bracket {
+        take_profit: Order,
+        stop_loss: Order,
+        cancel_on: OcoCancel,
+    },
+}
@@ -0,0 +1,262 @@
+//! This module contains the ledger of the balances of the users. Every movement of funds is a
+//! double-entry record: the same amount of an asset is debited to one account and credited to
+//! another, so the balances of each asset over all the accounts always sum up to zero.
+
+use crate::book::Book;
+use crate::event::RejectReason;
+use crate::order::{Order, Side};
+use crate::trade::Trade;
+use crate::Symbol;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Largest rounding error tolerated when balances of floating point amounts are compared.
+const TOLERANCE: f64 = 1e-6;
+
+/// Account of the ledger. Every user has an account of available funds and an account of the
+/// funds reserved for the orders of the user. The external account is where deposits come from,
+/// so its balance is the negative of everything deposited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Account {
+    External,
+    Available(u64),
+    Reserved(u64),
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Account::External => write!(f, "external"),
+            Account::Available(user_id) => write!(f, "available:{}", user_id),
+            Account::Reserved(user_id) => write!(f, "reserved:{}", user_id),
+        }
+    }
+}
+
+/// A single movement of an asset: the amount is debited to one account, increasing its balance,
+/// and credited to the other one, decreasing its balance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedgerEntry {
+    pub sequence: u64,
+    pub asset: Symbol,
+    pub debit: Account,
+    pub credit: Account,
+    pub amount: f64,
+}
+
+/// Balance of a user in an asset.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Balance {
+    pub available: f64,
+    pub reserved: f64,
+}
+
+/// Ledger keeps the entries and the resulting balances of all the accounts, together with the
+/// funds reserved for each order on the market.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    balances: HashMap<(Account, Symbol), f64>,
+    reservations: HashMap<(u64, u64), (Symbol, f64)>,
+    entries: Vec<LedgerEntry>,
+    sequence: u64,
+}
+
+impl Ledger {
+    /// Record an entry moving the amount of the asset from the credited account to the debited
+    /// one.
+    fn post(&mut self, asset: Symbol, debit: Account, credit: Account, amount: f64) {
+        self.sequence += 1;
+        self.entries.push(LedgerEntry {
+            sequence: self.sequence,
+            asset,
+            debit,
+            credit,
+            amount,
+        });
+        *self.balances.entry((debit, asset)).or_default() += amount;
+        *self.balances.entry((credit, asset)).or_default() -= amount;
+    }
+
+    fn balance_of(&self, account: Account, asset: Symbol) -> f64 {
+        self.balances.get(&(account, asset)).copied().unwrap_or(0.0)
+    }
+
+    /// Get the balance of the user in the asset.
+    pub fn balance(&self, user_id: u64, asset: Symbol) -> Balance {
+        Balance {
+            available: self.balance_of(Account::Available(user_id), asset),
+            reserved: self.balance_of(Account::Reserved(user_id), asset),
+        }
+    }
+
+    /// Add the amount of the asset to the available funds of the user.
+    pub fn deposit(&mut self, user_id: u64, asset: Symbol, amount: f64) {
+        self.post(
+            asset,
+            Account::Available(user_id),
+            Account::External,
+            amount,
+        );
+    }
+
+    /// Reserve funds of the user for the order, or reject the order if the user doesn't have
+    /// enough of them available.
+    pub fn reserve(
+        &mut self,
+        ids: (u64, u64),
+        asset: Symbol,
+        amount: f64,
+    ) -> Result<(), RejectReason> {
+        let (user_id, _) = ids;
+        if self.balance_of(Account::Available(user_id), asset) + TOLERANCE < amount {
+            return Err(RejectReason::InsufficientFunds);
+        }
+        self.post(
+            asset,
+            Account::Reserved(user_id),
+            Account::Available(user_id),
+            amount,
+        );
+        self.reservations.insert(ids, (asset, amount));
+        Ok(())
+    }
+
+    /// Release what is left of the funds reserved for the order once it has left the market.
+    pub fn release(&mut self, ids: (u64, u64)) {
+        let (user_id, _) = ids;
+        if let Some((asset, amount)) = self.reservations.remove(&ids) {
+            if amount > 0.0 {
+                self.post(
+                    asset,
+                    Account::Available(user_id),
+                    Account::Reserved(user_id),
+                    amount,
+                );
+            }
+        }
+    }
+
+    /// Release the funds reserved for all the orders.
+    pub fn release_all(&mut self) {
+        let ids = self.reservations.keys().copied().collect::<Vec<_>>();
+        for ids in ids {
+            self.release(ids);
+        }
+    }
+
+    /// Settle the trade out of the funds reserved for its orders: the buyer pays the price of the
+    /// trade in cash and the seller delivers the traded quantity of the security.
+    /// The fees of the trade are billed apart and aren't settled here.
+    pub fn settle(&mut self, trade: &Trade, cash: Symbol) {
+        let cost = trade.price.0 * trade.quantity as f64;
+        let quantity = trade.quantity as f64;
+        self.post(
+            cash,
+            Account::Available(trade.user_id_sell),
+            Account::Reserved(trade.user_id_buy),
+            cost,
+        );
+        self.consume((trade.user_id_buy, trade.user_order_id_buy), cost);
+        self.post(
+            trade.symbol,
+            Account::Available(trade.user_id_buy),
+            Account::Reserved(trade.user_id_sell),
+            quantity,
+        );
+        self.consume((trade.user_id_sell, trade.user_order_id_sell), quantity);
+    }
+
+    fn consume(&mut self, ids: (u64, u64), amount: f64) {
+        if let Some((_, reserved)) = self.reservations.get_mut(&ids) {
+            *reserved -= amount;
+        }
+    }
+
+    /// Get the entries recorded since they were last taken.
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    /// Take the entries recorded since they were last taken.
+    pub fn take_entries(&mut self) -> Vec<LedgerEntry> {
+        std::mem::take(&mut self.entries)
+    }
+
+    /// Get the sequence number of the last entry.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Check that the balances of every asset over all the accounts sum up to zero and that the
+    /// reserved balances of the users cover the reservations of their orders.
+    pub fn is_balanced(&self) -> bool {
+        let mut totals = HashMap::<Symbol, f64>::new();
+        let mut reserved = HashMap::<(Account, Symbol), f64>::new();
+        for (&(_, asset), &balance) in &self.balances {
+            *totals.entry(asset).or_default() += balance;
+        }
+        for (&(user_id, _), &(asset, amount)) in &self.reservations {
+            *reserved
+                .entry((Account::Reserved(user_id), asset))
+                .or_default() += amount;
+        }
+        let covered = |account, asset| {
+            let amount = reserved.get(&(account, asset)).copied().unwrap_or(0.0);
+            (self.balance_of(account, asset) - amount).abs() < TOLERANCE
+        };
+        totals.values().all(|total| total.abs() < TOLERANCE)
+            && reserved
+                .keys()
+                .all(|&(account, asset)| covered(account, asset))
+            && self.balances.keys().all(|&(account, asset)| {
+                !matches!(account, Account::Reserved(_)) || covered(account, asset)
+            })
+    }
+
+    pub fn balances(&self) -> impl Iterator<Item = ((Account, Symbol), f64)> + '_ {
+        self.balances.iter().map(|(&key, &balance)| (key, balance))
+    }
+
+    pub fn reservations(&self) -> impl Iterator<Item = ((u64, u64), (Symbol, f64))> + '_ {
+        self.reservations
+            .iter()
+            .map(|(&ids, &reservation)| (ids, reservation))
+    }
+
+    pub fn restore(
+        &mut self,
+        sequence: u64,
+        balances: impl IntoIterator<Item = ((Account, Symbol), f64)>,
+        reservations: impl IntoIterator<Item = ((u64, u64), (Symbol, f64))>,
+    ) {
+        self.sequence = sequence;
+        self.balances = balances.into_iter().collect();
+        self.reservations = reservations.into_iter().collect();
+    }
+}
+
+/// Get the asset and the amount of it that must be reserved for the order: the cost of a bid in
+/// cash at its limit price and the quantity of an ask in the security. Market bids are valued at
+/// the worst ask in the book, since they can't trade any higher. Other bids without a price of
+/// their own, such as stop orders without a limit, could trade at any price later on.
+pub(crate) fn required_funds(
+    order: &Order,
+    book: &Book,
+    cash: Symbol,
+) -> Result<(Symbol, f64), RejectReason> {
+    let quantity = order.remaining_quantity();
+    if order.side() == Side::Ask {
+        return Ok((order.symbol(), quantity as f64));
+    }
+    let price = match order {
+        Order::Market(_) => book.levels(Side::Ask).last().map(|level| level.price()),
+        _ => order.limit_price(),
+    };
+    match price {
+        Some(price) => Ok((cash, price.0 * quantity as f64)),
+        None => Err(RejectReason::UnknownCost),
+    }
+}
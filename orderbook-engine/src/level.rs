@@ -2,10 +2,14 @@
 use crate::order::{Order, Side};
 use crate::Price;
 
+use serde::{Deserialize, Serialize};
+
 use std::collections::VecDeque;
 
 /// Level represents a price level in an order book. The orders in a level are
-/// placed in a deque for efficient processing by matching algorithms.
+/// placed in a deque for efficient processing by matching algorithms. Hidden orders
+/// are kept at the front of the deque, behind all the displayed orders.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Level {
     price: Price,
     side: Side,
@@ -22,13 +26,23 @@ impl Level {
         }
     }
 
-    /// Add an order to the level.
+    /// Add an order to the level. A displayed order goes after the other displayed
+    /// orders, but ahead of the hidden ones.
     pub fn add(&mut self, order: Order) {
         debug_assert!(
             order.side() == self.side,
             "Order side does not match level side"
         );
-        self.orders.push_front(order);
+        if order.is_hidden() {
+            self.orders.push_front(order);
+        } else {
+            let hidden = self
+                .orders
+                .iter()
+                .take_while(|order| order.is_hidden())
+                .count();
+            self.orders.insert(hidden, order);
+        }
     }
 
     /// Cancel an order given by order ids.
@@ -41,6 +55,11 @@ impl Level {
         None
     }
 
+    /// Get the side of this level.
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
     /// Get the price of this level.
     pub fn price(&self) -> Price {
         self.price
@@ -54,6 +73,23 @@ impl Level {
         &mut self.orders
     }
 
+    /// Get the total displayed quantity of all orders in this level. All-or-none orders can't
+    /// be traded against in part, so they aren't counted either.
+    pub fn quantity(&self) -> u64 {
+        self.orders
+            .iter()
+            .filter(|order| !order.is_hidden() && !order.is_all_or_none())
+            .map(|order| order.quantity())
+            .sum()
+    }
+
+    /// Find an order given by order ids.
+    pub fn get(&self, user_id: u64, user_order_id: u64) -> Option<&Order> {
+        self.orders
+            .iter()
+            .find(|order| order.user_id() == user_id && order.user_order_id() == user_order_id)
+    }
+
     /// Return true if this level doesn't contain any orders.
     pub fn is_empty(&self) -> bool {
         self.orders.is_empty()
@@ -1,20 +1,44 @@
 use ordered_float::OrderedFloat;
 use string_interner::symbol::SymbolU32;
 
+mod auction;
 mod book;
+mod clock;
+mod event;
+mod fee;
+mod group;
+mod ledger;
 mod level;
 mod market;
 mod matcher;
 mod order;
+mod position;
+mod risk;
+mod session;
+mod snapshot;
 mod trade;
+mod trigger;
 
 pub type Price = OrderedFloat<f64>;
 pub type Symbol = SymbolU32;
 
+/// The minimum price increment. Prices are whole numbers in the published formats.
+pub const TICK_SIZE: f64 = 1.0;
+
 pub mod prelude {
-    pub use super::market::Market;
+    pub use super::auction::Equilibrium;
+    pub use super::clock::{Clock, ManualClock, SystemClock};
+    pub use super::event::{Event, RejectReason, SequencedEvent};
+    pub use super::fee::{FeeRates, FeeTotal};
+    pub use super::group::OcoCancel;
+    pub use super::ledger::{Account, Balance, LedgerEntry};
+    pub use super::market::{Market, MassCancel};
     pub use super::matcher::*;
-    pub use super::order::{Order, Side};
+    pub use super::order::{Order, Peg, PegReference, PostOnly, Side, Trail};
+    pub use super::position::{Position, PositionReport};
+    pub use super::risk::RiskLimits;
+    pub use super::session::{CircuitBreaker, Phase};
+    pub use super::snapshot::Snapshot;
     pub use super::trade::Trade;
     pub use super::Price;
     pub use super::Symbol;
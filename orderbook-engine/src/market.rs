@@ -1,25 +1,45 @@
 //! This modulev contains the definition of a market. A market is a collection of order books
 //! for a given set of securities.
 
-use crate::book::Book;
+use crate::auction::Equilibrium;
+use crate::book::{Book, Execution};
+use crate::clock::{Clock, ManualClock};
+use crate::event::{Event, RejectReason, SequencedEvent};
+use crate::fee::{Billing, FeeRates, FeeSchedule, FeeTotal};
+use crate::group::{Link, OcoCancel};
+use crate::ledger::{self, Balance, Ledger, LedgerEntry};
 use crate::matcher::Matcher;
 use crate::order::{Order, Side};
+use crate::position::{Position, PositionReport, Positions};
+use crate::risk::{Risk, RiskLimits};
+use crate::session::{CircuitBreaker, Phase};
+use crate::snapshot::{
+    serde_symbol, BalanceSnapshot, BookSnapshot, FeeTotalSnapshot, PositionSnapshot,
+    ReservationSnapshot, Snapshot,
+};
 use crate::trade::Trade;
 use crate::{Price, Symbol};
 
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use string_interner::StringInterner;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 /// Helper structure to tracker orders by their ids. This is necessary when we want for example
 /// to cancel an order, but know only its ids. Since we need the symbol to choose a correct order
 /// book and a price to choose a price level in the book to cancnel an order, we keep track of
 /// this information with this structure.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Index {
     pub user_id: u64,
     pub user_order_id: u64,
+    #[serde(with = "serde_symbol")]
     pub symbol: Symbol,
     pub price: Price,
     pub side: Side,
+    pub order_id: u64,
 }
 
 impl Index {
@@ -30,6 +50,7 @@ impl Index {
             symbol: order.symbol(),
             price: order.price(),
             side: order.side(),
+            order_id: order.order_id(),
         }
     }
 
@@ -38,12 +59,61 @@ impl Index {
     }
 }
 
+/// Filter of the orders cancelled by a mass cancel. Each of the user, the security and the side
+/// narrows the orders down if given, so an empty filter matches every order on the market.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MassCancel {
+    pub user_id: Option<u64>,
+    pub symbol: Option<Symbol>,
+    pub side: Option<Side>,
+}
+
+impl MassCancel {
+    fn matches(&self, index: &Index) -> bool {
+        self.user_id.is_none_or(|user_id| user_id == index.user_id)
+            && self.symbol.is_none_or(|symbol| symbol == index.symbol)
+            && self.side.is_none_or(|side| side == index.side)
+    }
+}
+
 /// Market is a collection of order books for a given set of securities. It also contains a map
-/// of all index structs for all orders currently on the market.
+/// of all index structs for all orders currently on the market, the links of the orders that are
+/// in groups, the names of the securities and the events produced since they were last taken.
+/// Orders have to pass the pre-trade risk checks of their users before they are accepted, and the
+/// trades update the positions of the users. With funding turned on, orders must also be backed
+/// by the balances of their users in the ledger. Orders must not reuse the ids of the orders on
+/// the market, or optionally those of any order accepted since the market was last cleared.
+/// Orders trade at the fee rates of their users, and the fees are added up for billing.
+/// Operators can halt the whole market and disable users with the kill switch, both of which
+/// reject new orders. The market numbers the accepted orders, the trades and the events, and
+/// stamps the trades and the events with the time of its clock, which stands still unless another
+/// one is set. Apart from that, the market doesn't read the time itself: it only knows the time it
+/// was last advanced to.
 pub struct Market<M> {
     books: HashMap<Symbol, Book>,
     indices: HashMap<(u64, u64), Index>,
+    // Number of indexed orders of every user, kept along with the indices.
+    open_orders: HashMap<u64, usize>,
+    groups: HashMap<(u64, u64), Link>,
+    symbols: StringInterner,
     matcher: M,
+    events: Vec<SequencedEvent>,
+    clock: Box<dyn Clock>,
+    sequence: u64,
+    last_order_id: u64,
+    last_trade_id: u64,
+    trading: bool,
+    breaker: Option<CircuitBreaker>,
+    now: Duration,
+    risk: Risk,
+    positions: Positions,
+    ledger: Ledger,
+    cash: Option<Symbol>,
+    fees: FeeSchedule,
+    billing: Billing,
+    halted: bool,
+    disabled_users: HashSet<u64>,
+    used_ids: Option<HashSet<(u64, u64)>>,
 }
 
 impl<M: Matcher> Market<M> {
@@ -52,44 +122,852 @@ impl<M: Matcher> Market<M> {
         Self {
             books: HashMap::new(),
             indices: HashMap::new(),
+            open_orders: HashMap::new(),
+            groups: HashMap::new(),
+            symbols: StringInterner::default(),
             matcher,
+            events: Vec::new(),
+            clock: Box::new(ManualClock::default()),
+            sequence: 0,
+            last_order_id: 0,
+            last_trade_id: 0,
+            trading: true,
+            breaker: None,
+            now: Duration::ZERO,
+            risk: Risk::default(),
+            positions: Positions::default(),
+            ledger: Ledger::default(),
+            cash: None,
+            fees: FeeSchedule::default(),
+            billing: Billing::default(),
+            halted: false,
+            disabled_users: HashSet::new(),
+            used_ids: None,
         }
     }
 
-    /// Add an order to the market.
-    pub fn add(&mut self, order: Order) -> Vec<Trade> {
-        let index = Index::from_order(&order);
-        let (order_persists, trades) = self
+    /// Restore a market from a snapshot.
+    pub fn restore(matcher: M, snapshot: Snapshot) -> Self {
+        let mut positions = Positions::default();
+        positions.restore(snapshot.positions.into_iter().map(|position| {
+            let key = (position.user_id, position.symbol);
+            let position = Position {
+                quantity: position.position,
+                average_cost: position.average_cost,
+                realized_pnl: position.realized_pnl,
+            };
+            (key, position)
+        }));
+        let mut ledger = Ledger::default();
+        ledger.restore(
+            snapshot.ledger_sequence,
+            snapshot
+                .balances
+                .into_iter()
+                .map(|balance| ((balance.account, balance.asset), balance.balance)),
+            snapshot.reservations.into_iter().map(|reservation| {
+                let ids = (reservation.user_id, reservation.user_order_id);
+                (ids, (reservation.asset, reservation.amount))
+            }),
+        );
+        let mut billing = Billing::default();
+        billing.restore(
+            snapshot
+                .fee_totals
+                .into_iter()
+                .map(|total| ((total.user_id, total.symbol), total.total)),
+        );
+        let mut open_orders = HashMap::new();
+        for index in &snapshot.indices {
+            *open_orders.entry(index.user_id).or_default() += 1;
+        }
+        Self {
+            books: snapshot
+                .books
+                .into_iter()
+                .map(|BookSnapshot { symbol, book }| (symbol, book))
+                .collect(),
+            indices: snapshot
+                .indices
+                .into_iter()
+                .map(|index| (index.ids(), index))
+                .collect(),
+            open_orders,
+            groups: snapshot.groups.into_iter().collect(),
+            symbols: snapshot.symbols,
+            matcher,
+            events: Vec::new(),
+            clock: Box::new(ManualClock::default()),
+            sequence: snapshot.sequence,
+            last_order_id: snapshot.last_order_id,
+            last_trade_id: snapshot.last_trade_id,
+            trading: true,
+            breaker: None,
+            now: Duration::ZERO,
+            risk: Risk::default(),
+            positions,
+            ledger,
+            cash: None,
+            fees: FeeSchedule::default(),
+            billing,
+            halted: snapshot.halted,
+            disabled_users: snapshot.disabled_users.into_iter().collect(),
+            used_ids: snapshot
+                .used_ids
+                .map(|used_ids| used_ids.into_iter().collect()),
+        }
+    }
+
+    /// Take a snapshot of the market. The position is an opaque marker of the last command
+    /// applied to the market, e.g. its sequence number in a journal.
+    pub fn snapshot(&self, position: u64) -> Snapshot {
+        let mut books = self
+            .books
+            .iter()
+            .map(|(&symbol, book)| BookSnapshot {
+                symbol,
+                book: book.clone(),
+            })
+            .collect::<Vec<_>>();
+        books.sort_by_key(|book| book.symbol);
+        let mut indices = self.indices.values().copied().collect::<Vec<_>>();
+        indices.sort_by_key(|index| index.ids());
+        let mut groups = self
+            .groups
+            .iter()
+            .map(|(&ids, link)| (ids, link.clone()))
+            .collect::<Vec<_>>();
+        groups.sort_by_key(|(ids, _)| *ids);
+        let mut positions = self
+            .positions
+            .iter()
+            .map(|((user_id, symbol), position)| PositionSnapshot {
+                user_id,
+                symbol,
+                position: position.quantity,
+                average_cost: position.average_cost,
+                realized_pnl: position.realized_pnl,
+            })
+            .collect::<Vec<_>>();
+        positions.sort_by_key(|position| (position.user_id, position.symbol));
+        let mut balances = self
+            .ledger
+            .balances()
+            .map(|((account, asset), balance)| BalanceSnapshot {
+                account,
+                asset,
+                balance,
+            })
+            .collect::<Vec<_>>();
+        balances.sort_by_key(|balance| (balance.account, balance.asset));
+        let mut reservations = self
+            .ledger
+            .reservations()
+            .map(
+                |((user_id, user_order_id), (asset, amount))| ReservationSnapshot {
+                    user_id,
+                    user_order_id,
+                    asset,
+                    amount,
+                },
+            )
+            .collect::<Vec<_>>();
+        reservations.sort_by_key(|reservation| (reservation.user_id, reservation.user_order_id));
+        let mut fee_totals = self
+            .billing
+            .iter()
+            .map(|((user_id, symbol), total)| FeeTotalSnapshot {
+                user_id,
+                symbol,
+                total,
+            })
+            .collect::<Vec<_>>();
+        fee_totals.sort_by_key(|total| (total.user_id, total.symbol));
+        let mut disabled_users = self.disabled_users.iter().copied().collect::<Vec<_>>();
+        disabled_users.sort_unstable();
+        let used_ids = self.used_ids.as_ref().map(|used_ids| {
+            let mut used_ids = used_ids.iter().copied().collect::<Vec<_>>();
+            used_ids.sort_unstable();
+            used_ids
+        });
+        Snapshot {
+            position,
+            symbols: self.symbols.clone(),
+            books,
+            indices,
+            groups,
+            positions,
+            balances,
+            reservations,
+            fee_totals,
+            halted: self.halted,
+            disabled_users,
+            sequence: self.sequence,
+            last_order_id: self.last_order_id,
+            last_trade_id: self.last_trade_id,
+            used_ids,
+            ledger_sequence: self.ledger.sequence(),
+        }
+    }
+
+    /// Get the symbol of a security by its name, registering the name if it is new.
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        self.symbols.get_or_intern(name)
+    }
+
+    /// Iterate over the displayed orders on one side of the book for the given security in the
+    /// order of their priority. Hidden orders are left out.
+    pub fn orders(&self, symbol: Symbol, side: Side) -> impl Iterator<Item = &Order> {
+        self.books
+            .get(&symbol)
+            .into_iter()
+            .flat_map(move |book| book.levels(side))
+            .flat_map(|level| level.orders().iter().rev())
+            .filter(|order| !order.is_hidden())
+    }
+
+    /// Check whether an order is still on the market, either in a book or waiting for its stop
+    /// price.
+    pub fn contains(&self, user_id: u64, user_order_id: u64) -> bool {
+        self.indices.contains_key(&(user_id, user_order_id))
+    }
+
+    /// Get the name of a security by its symbol.
+    pub fn symbol_name(&self, symbol: Symbol) -> Option<&str> {
+        self.symbols.resolve(symbol)
+    }
+
+    /// Reject orders reusing the ids of filled or cancelled orders too, not just those of the
+    /// orders on the market, until the market is cleared. Off by default.
+    pub fn set_reject_reused_ids(&mut self, reject: bool) {
+        match (reject, &self.used_ids) {
+            (true, None) => self.used_ids = Some(HashSet::new()),
+            (false, _) => self.used_ids = None,
+            _ => (),
+        }
+    }
+
+    /// Set the clock the trades and the events are stamped with.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Turn trading on or off. When trading is off, orders that would cross the book are
+    /// rejected instead of being matched. Trading is on by default.
+    pub fn set_trading(&mut self, trading: bool) {
+        self.trading = trading;
+    }
+
+    /// Set the circuit breaker of all the books, or turn it off.
+    pub fn set_circuit_breaker(&mut self, breaker: Option<CircuitBreaker>) {
+        self.breaker = breaker;
+        for book in self.books.values_mut() {
+            book.set_band(breaker.map(|breaker| breaker.band));
+        }
+    }
+
+    /// Advance the time of the market, carrying out the scheduled changes of trading phases that
+    /// are due, e.g. reopening the books halted by the circuit breaker. The changes are carried
+    /// out one by one at the time they were due, so the outcome doesn't depend on how often the
+    /// market is advanced.
+    pub fn advance(&mut self, now: Duration) {
+        while let Some((at, symbol, phase)) = self
             .books
-            .entry(order.symbol())
-            .or_default()
-            .add(order, &mut self.matcher);
-        if order_persists {
+            .iter()
+            .filter_map(|(&symbol, book)| match book.scheduled() {
+                Some((phase, at)) if at <= now => Some((at, symbol, phase)),
+                _ => None,
+            })
+            .min_by_key(|&(at, symbol, _)| (at, symbol))
+        {
+            self.now = at;
+            if self.set_phase(symbol, phase).is_none() {
+                // The book has moved on to a phase the change can't follow.
+                book_entry(&mut self.books, symbol, self.breaker).unschedule();
+                continue;
+            }
+            if phase == Phase::Auction {
+                let reopening = self
+                    .breaker
+                    .map_or(Duration::ZERO, |breaker| breaker.reopening);
+                self.books
+                    .get_mut(&symbol)
+                    .expect("Book not found")
+                    .schedule(Phase::Continuous, at + reopening);
+            }
+        }
+        self.now = now;
+    }
+
+    /// Turn funding on with the given asset as cash, or turn it off. With funding on, bids reserve
+    /// their cost in cash and asks their quantity of the security from the available balances
+    /// of their users, and trades are settled out of the reserved funds. Funding is off by
+    /// default.
+    pub fn set_funding(&mut self, cash: Option<Symbol>) {
+        self.cash = cash;
+    }
+
+    /// Add the amount of the asset to the available balance of a user.
+    pub fn deposit(&mut self, user_id: u64, asset: Symbol, amount: f64) {
+        self.ledger.deposit(user_id, asset, amount);
+    }
+
+    /// Get the balance of a user in an asset.
+    pub fn balance(&self, user_id: u64, asset: Symbol) -> Balance {
+        self.ledger.balance(user_id, asset)
+    }
+
+    /// Get the ledger entries recorded since they were last taken. Restored markets number
+    /// their entries on from the last entry before the snapshot.
+    pub fn ledger_entries(&self) -> &[LedgerEntry] {
+        self.ledger.entries()
+    }
+
+    /// Take the ledger entries recorded since they were last taken, e.g. to export them.
+    pub fn take_ledger_entries(&mut self) -> Vec<LedgerEntry> {
+        self.ledger.take_entries()
+    }
+
+    /// Check that every asset is balanced over all the accounts of the ledger and that the funds
+    /// reserved for the orders on the market are exactly the reserved balances.
+    pub fn audit_ledger(&self) -> bool {
+        self.ledger.is_balanced()
+    }
+
+    /// Set the fee rates that apply where the fee schedule has no rates for the tier of the user.
+    pub fn set_default_fee_rates(&mut self, rates: FeeRates) {
+        self.fees.set_default_rates(rates);
+    }
+
+    /// Set the fee rates of a user tier in a security, or in all the securities if none is given.
+    pub fn set_fee_rates(&mut self, symbol: Option<Symbol>, tier: u32, rates: FeeRates) {
+        self.fees.set_rates(symbol, tier, rates);
+    }
+
+    /// Put a user in a fee tier. Users are in tier zero unless put elsewhere.
+    pub fn set_fee_tier(&mut self, user_id: u64, tier: u32) {
+        self.fees.set_tier(user_id, tier);
+    }
+
+    /// Get the fee totals of every user in every security the user has traded, ordered by the
+    /// user ids and the security names.
+    pub fn fee_totals(&self) -> Vec<(u64, Symbol, FeeTotal)> {
+        let mut totals = self
+            .billing
+            .iter()
+            .map(|((user_id, symbol), total)| (user_id, symbol, total))
+            .collect::<Vec<_>>();
+        totals.sort_by_key(|&(user_id, symbol, _)| (user_id, self.symbol_name(symbol)));
+        totals
+    }
+
+    /// Set the risk limits of the users without limits of their own.
+    pub fn set_default_risk_limits(&mut self, limits: RiskLimits) {
+        self.risk.set_default_limits(limits);
+    }
+
+    /// Set the risk limits of a user.
+    pub fn set_risk_limits(&mut self, user_id: u64, limits: RiskLimits) {
+        self.risk.set_limits(user_id, limits);
+    }
+
+    /// Get the net position of a user in a security, positive if long and negative if short.
+    pub fn position(&self, user_id: u64, symbol: Symbol) -> i64 {
+        self.positions.get(user_id, symbol).quantity
+    }
+
+    /// Get the positions of a user in all the securities the user has traded, with their profit
+    /// and loss marked to the current state of the books, ordered by the security names.
+    pub fn position_reports(&self, user_id: u64) -> Vec<PositionReport> {
+        let mut reports = self
+            .positions
+            .iter()
+            .filter(|&((id, _), _)| id == user_id)
+            .map(|((_, symbol), position)| {
+                let mark_price = self.books.get(&symbol).and_then(Book::mark_price);
+                PositionReport {
+                    symbol,
+                    position,
+                    mark_price,
+                    unrealized_pnl: mark_price.map_or(0.0, |price| position.unrealized_pnl(price)),
+                }
+            })
+            .collect::<Vec<_>>();
+        reports.sort_by_key(|report| self.symbol_name(report.symbol));
+        reports
+    }
+
+    /// Add an order to the market.
+    pub fn add(&mut self, mut order: Order) -> Vec<Trade> {
+        order.set_fee_rates(self.fees.rates(order.user_id(), order.symbol()));
+        let trading = self.trading;
+        let open_orders = self
+            .open_orders
+            .get(&order.user_id())
+            .copied()
+            .unwrap_or_default();
+        let position = self.position(order.user_id(), order.symbol());
+        let ids = (order.user_id(), order.user_order_id());
+        let unique = self.check_ids(ids);
+        let halted = self.halted;
+        let disabled = self.disabled_users.contains(&order.user_id());
+        let cash = self.cash;
+        let risk = &self.risk;
+        let ledger = &mut self.ledger;
+        let book = book_entry(&mut self.books, order.symbol(), self.breaker);
+        let allowed = if disabled {
+            Err(RejectReason::UserDisabled)
+        } else if halted {
+            Err(RejectReason::MarketHalted)
+        } else {
+            Ok(())
+        };
+        let result = unique
+            .and(allowed)
+            .and_then(|()| book.phase().check_order(&order))
+            // Posting may reprice the order, so the risk limits are checked only afterwards.
+            .and_then(|()| book.post(&mut order))
+            .and_then(|()| risk.check(&order, book, open_orders, position))
+            .and_then(|()| {
+                if !trading && book.crosses(&order) {
+                    Err(RejectReason::WouldCross)
+                } else {
+                    Ok(())
+                }
+            })
+            .and_then(|()| match cash {
+                Some(cash) => {
+                    let (asset, amount) = ledger::required_funds(&order, book, cash)?;
+                    let ids = (order.user_id(), order.user_order_id());
+                    ledger.reserve(ids, asset, amount)
+                }
+                None => Ok(()),
+            });
+        // The index is taken only now, since posting may have repriced the order.
+        let mut index = Index::from_order(&order);
+        if let Err(reason) = result {
+            self.publish_reject(&index, reason);
+            return Vec::new();
+        }
+        self.last_order_id += 1;
+        order.set_order_id(self.last_order_id);
+        index.order_id = self.last_order_id;
+        if let Some(used_ids) = &mut self.used_ids {
+            used_ids.insert(ids);
+        }
+        let top = top_of_book(book);
+        let indicative = book.indicative();
+        let mut execution = book.add(order, &mut self.matcher);
+        if book.contains(&index) {
             self.indices.insert(index.ids(), index);
+            *self.open_orders.entry(index.user_id).or_default() += 1;
+        }
+        self.stamp(&mut execution.trades);
+        self.settle(&execution);
+        self.remove_done(index.symbol, &execution);
+        self.release_gone(index.ids());
+        self.publish_order(&index);
+        self.publish_trades(&execution.trades);
+        self.publish_cancelled(&execution.cancelled);
+        self.publish_top_of_book(index.symbol, top);
+        self.publish_indicative(index.symbol, indicative);
+        self.publish_halt(index.symbol, &execution);
+        self.update_groups(&execution);
+        execution.trades
+    }
+
+    /// Add a one-cancels-other pair of orders to the market. Depending on `cancel_on`, a partial
+    /// or a complete fill of one of the orders cancels the other one. If the first order cancels
+    /// the second one right away, the second one is never added and is reported as cancelled. If
+    /// either order has a duplicate id, the whole pair is rejected.
+    pub fn add_oco(&mut self, first: Order, second: Order, cancel_on: OcoCancel) -> Vec<Trade> {
+        let first_ids = (first.user_id(), first.user_order_id());
+        let second_ids = (second.user_id(), second.user_order_id());
+        // Linking an order with a duplicate id would replace the links of the order that has the
+        // id already, and the other order would lose its counterpart, so neither is added.
+        let unique = self
+            .check_ids(first_ids)
+            .and_then(|()| self.check_ids(second_ids));
+        if let Err(reason) = unique.and_then(|()| {
+            if first_ids == second_ids {
+                Err(RejectReason::DuplicateOrderId)
+            } else {
+                Ok(())
+            }
+        }) {
+            self.publish_reject(&Index::from_order(&first), reason);
+            self.publish_reject(&Index::from_order(&second), reason);
+            return Vec::new();
+        }
+        self.groups.insert(
+            first_ids,
+            Link::PendingOco {
+                other: second,
+                cancel_on,
+            },
+        );
+        let mut trades = self.add(first);
+        // Without the pending link, the first order has already cancelled the second one.
+        if let Some(Link::PendingOco { other, cancel_on }) = self.groups.remove(&first_ids) {
+            if self.indices.contains_key(&first_ids) {
+                self.groups.insert(
+                    first_ids,
+                    Link::Oco {
+                        other: second_ids,
+                        cancel_on,
+                    },
+                );
+                self.groups.insert(
+                    second_ids,
+                    Link::Oco {
+                        other: first_ids,
+                        cancel_on,
+                    },
+                );
+            }
+            trades.extend(self.add(other));
+            self.unlink_gone(second_ids);
         }
-        self.log_order(&index);
-        self.log_top_of_book(&index);
-        self.log_trades(&trades);
         trades
     }
 
-    /// Cancel an order given by order ids.
+    /// Add a bracket order to the market. Only the entry order is added right away, the
+    /// take-profit and stop-loss orders are added as a one-cancels-other pair once the entry
+    /// order is completely filled, where `cancel_on` tells which fill of one of them cancels the
+    /// other one.
+    pub fn add_bracket(
+        &mut self,
+        entry: Order,
+        take_profit: Order,
+        stop_loss: Order,
+        cancel_on: OcoCancel,
+    ) -> Vec<Trade> {
+        let entry_ids = (entry.user_id(), entry.user_order_id());
+        if let Err(reason) = self.check_ids(entry_ids) {
+            self.publish_reject(&Index::from_order(&entry), reason);
+            return Vec::new();
+        }
+        self.groups.insert(
+            entry_ids,
+            Link::Bracket {
+                take_profit,
+                stop_loss,
+                cancel_on,
+            },
+        );
+        let trades = self.add(entry);
+        self.unlink_gone(entry_ids);
+        trades
+    }
+
+    /// Check that the ids aren't those of an order on the market or, if the reuse of ids is
+    /// rejected, of any order accepted since the market was last cleared.
+    fn check_ids(&self, ids: (u64, u64)) -> Result<(), RejectReason> {
+        let used = self
+            .used_ids
+            .as_ref()
+            .is_some_and(|used_ids| used_ids.contains(&ids));
+        if used || self.indices.contains_key(&ids) {
+            Err(RejectReason::DuplicateOrderId)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Cancel an order given by order ids. Cancelling an order of a group dissolves the group.
     pub fn cancel(&mut self, user_id: u64, user_order_id: u64) -> Option<Order> {
+        let ids = (user_id, user_order_id);
+        if let Some(index) = self.indices.get(&ids) {
+            if let Err(reason) = self.books[&index.symbol].phase().check_cancel() {
+                self.publish(Event::Rejected {
+                    user_id,
+                    user_order_id,
+                    reason,
+                });
+                return None;
+            }
+        }
+        let order_id = self.indices.get(&ids).map_or(0, |index| index.order_id);
+        self.unlink(ids);
+        let removed_order = self.remove(
+            ids,
+            Event::Accepted {
+                user_id,
+                user_order_id,
+                order_id,
+            },
+        );
+        if removed_order.is_none() {
+            self.publish(Event::Rejected {
+                user_id,
+                user_order_id,
+                reason: RejectReason::UnknownOrder,
+            });
+        }
+        removed_order
+    }
+
+    /// Move the instrument on to the given trading phase, uncrossing its book when continuous
+    /// trading starts or a closing auction ends. Returns `None` without publishing anything if
+    /// the current phase can't be followed by the given one.
+    pub fn set_phase(&mut self, symbol: Symbol, phase: Phase) -> Option<Vec<Trade>> {
+        let book = book_entry(&mut self.books, symbol, self.breaker);
+        let top = top_of_book(book);
+        let mut execution = book.set_phase(phase, &mut self.matcher)?;
+        self.stamp(&mut execution.trades);
+        self.settle(&execution);
+        self.remove_done(symbol, &execution);
+        self.publish_phase(symbol, phase);
+        self.publish_trades(&execution.trades);
+        self.publish_cancelled(&execution.cancelled);
+        self.publish_top_of_book(symbol, top);
+        self.publish_indicative(symbol, None);
+        self.publish_halt(symbol, &execution);
+        self.update_groups(&execution);
+        Some(execution.trades)
+    }
+
+    /// Cancel all the orders that match the filter, in the order of their ids, reporting each of
+    /// them as cancelled. Orders in securities whose trading phase doesn't allow cancelling are
+    /// left on the market and reported as rejected. Returns the cancelled orders.
+    pub fn mass_cancel(&mut self, filter: MassCancel) -> Vec<Order> {
+        let mut ids = self
+            .indices
+            .values()
+            .filter(|index| filter.matches(index))
+            .map(Index::ids)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        let mut cancelled = Vec::new();
+        for (user_id, user_order_id) in ids {
+            // An earlier cancellation may have removed the order already, e.g. by triggering
+            // something that filled it.
+            let index = match self.indices.get(&(user_id, user_order_id)) {
+                Some(index) => index,
+                None => continue,
+            };
+            if let Err(reason) = self.books[&index.symbol].phase().check_cancel() {
+                self.publish(Event::Rejected {
+                    user_id,
+                    user_order_id,
+                    reason,
+                });
+                continue;
+            }
+            self.unlink((user_id, user_order_id));
+            let event = Event::Cancelled {
+                user_id,
+                user_order_id,
+            };
+            cancelled.extend(self.remove((user_id, user_order_id), event));
+        }
+        cancelled
+    }
+
+    /// Halt or resume trading on the whole market. While the market is halted, new orders are
+    /// rejected whatever the trading phases of their securities, but orders can be cancelled.
+    pub fn set_halted(&mut self, halted: bool) {
+        if self.halted != halted {
+            self.halted = halted;
+            self.publish(Event::MarketHalt { halted });
+        }
+    }
+
+    /// Check whether trading on the whole market is halted.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Disable the user with the kill switch, cancelling all of the orders of the user and
+    /// rejecting new ones until the user is enabled again. Returns the cancelled orders.
+    pub fn disable_user(&mut self, user_id: u64) -> Vec<Order> {
+        self.disabled_users.insert(user_id);
+        self.mass_cancel(MassCancel {
+            user_id: Some(user_id),
+            ..Default::default()
+        })
+    }
+
+    /// Enable a user disabled by the kill switch.
+    pub fn enable_user(&mut self, user_id: u64) {
+        self.disabled_users.remove(&user_id);
+    }
+
+    /// Check whether the user has been disabled by the kill switch.
+    pub fn is_disabled(&self, user_id: u64) -> bool {
+        self.disabled_users.contains(&user_id)
+    }
+
+    /// Remove an order given by order ids from the market, publishing the given event followed
+    /// by whatever the removal caused.
+    fn remove(&mut self, ids: (u64, u64), event: Event) -> Option<Order> {
         // Find the index of the order to cancel, find the book and remove
         // the order from the book.
-        let index = self
-            .indices
-            .get(&(user_id, user_order_id))
-            .expect("Index not found");
-        let book = self.books.get_mut(&index.symbol).expect("Book not found");
-        let removed_order = book.remove(index);
+        let index = self.indices.remove(&ids)?;
+        close_order(&mut self.open_orders, index.user_id);
         // We don't want to remove a book when its empty. It is an unsual situation
         // to have no orders for a specific security at all in the first place, and
         // even if it happens, we probably will have a new order for it soon. Therefore,
         // it is enough to update indices only.
-        let ids = index.ids();
-        self.indices.remove(&ids).expect("Index not found");
-        removed_order
+        let book = self.books.get_mut(&index.symbol).expect("Book not found");
+        let top = top_of_book(book);
+        let indicative = book.indicative();
+        let removed_order = book.remove(&index)?;
+        self.ledger.release(ids);
+        // The cancelled order might have been the reference price of some pegged orders.
+        let mut execution = book.reprice(&mut self.matcher);
+        self.stamp(&mut execution.trades);
+        self.settle(&execution);
+        self.remove_done(index.symbol, &execution);
+        self.publish(event);
+        self.publish_trades(&execution.trades);
+        self.publish_cancelled(&execution.cancelled);
+        self.publish_top_of_book(index.symbol, top);
+        self.publish_indicative(index.symbol, indicative);
+        self.publish_halt(index.symbol, &execution);
+        self.update_groups(&execution);
+        Some(removed_order)
+    }
+
+    /// Act on the links of the orders that traded or were cancelled during the execution:
+    /// cancel the other orders of one-cancels-other pairs and activate the children of filled
+    /// bracket entries.
+    fn update_groups(&mut self, execution: &Execution) {
+        if self.groups.is_empty() {
+            return;
+        }
+        let mut traded = Vec::new();
+        for trade in &execution.trades {
+            for ids in [
+                (trade.user_id_buy, trade.user_order_id_buy),
+                (trade.user_id_sell, trade.user_order_id_sell),
+            ] {
+                if !traded.contains(&ids) {
+                    traded.push(ids);
+                }
+            }
+        }
+        let cancelled = execution
+            .cancelled
+            .iter()
+            .map(|order| (order.user_id(), order.user_order_id()))
+            .collect::<Vec<_>>();
+        for &ids in traded.iter().chain(&cancelled) {
+            let gone = !self.indices.contains_key(&ids);
+            let filled = gone && !cancelled.contains(&ids);
+            let triggers = |cancel_on| match cancel_on {
+                OcoCancel::PartialFill => traded.contains(&ids),
+                OcoCancel::Fill => filled,
+            };
+            match self.groups.get(&ids) {
+                Some(&Link::Oco { other, cancel_on }) if triggers(cancel_on) => {
+                    self.unlink(ids);
+                    self.remove(
+                        other,
+                        Event::Cancelled {
+                            user_id: other.0,
+                            user_order_id: other.1,
+                        },
+                    );
+                }
+                Some(&Link::PendingOco { cancel_on, .. }) if triggers(cancel_on) => {
+                    if let Some(Link::PendingOco { other, .. }) = self.groups.remove(&ids) {
+                        self.publish_cancelled(&[other]);
+                    }
+                }
+                Some(Link::Bracket { .. }) if filled => {
+                    if let Some(Link::Bracket {
+                        take_profit,
+                        stop_loss,
+                        cancel_on,
+                    }) = self.groups.remove(&ids)
+                    {
+                        self.add_oco(take_profit, stop_loss, cancel_on);
+                    }
+                }
+                Some(Link::Oco { .. }) | Some(Link::Bracket { .. }) if gone => self.unlink(ids),
+                _ => (),
+            }
+        }
+    }
+
+    /// Drop the link of an order, together with the link back to it from the other order of a
+    /// one-cancels-other pair.
+    fn unlink(&mut self, ids: (u64, u64)) {
+        if let Some(Link::Oco { other, .. }) = self.groups.remove(&ids) {
+            self.groups.remove(&other);
+        }
+    }
+
+    /// Drop the link of an order that has left the market without acting on its group, e.g.
+    /// because it has been rejected.
+    fn unlink_gone(&mut self, ids: (u64, u64)) {
+        if !self.indices.contains_key(&ids) {
+            self.unlink(ids);
+        }
+    }
+
+    /// Number the trades of an execution and stamp them with the time.
+    fn stamp(&mut self, trades: &mut [Trade]) {
+        for trade in trades {
+            self.last_trade_id += 1;
+            trade.trade_id = self.last_trade_id;
+            trade.timestamp = self.clock.now();
+        }
+    }
+
+    /// Settle the trades of the execution in the ledger, if funding is on.
+    fn settle(&mut self, execution: &Execution) {
+        if let Some(cash) = self.cash {
+            for trade in &execution.trades {
+                self.ledger.settle(trade, cash);
+            }
+        }
+    }
+
+    /// Release the funds reserved for an order if it isn't on the market anymore.
+    fn release_gone(&mut self, ids: (u64, u64)) {
+        if !self.indices.contains_key(&ids) {
+            self.ledger.release(ids);
+        }
+    }
+
+    /// Remove the indices of the orders that have left the book during the execution, either
+    /// because they have been completely filled or because they have been cancelled, and release
+    /// the funds reserved for them.
+    fn remove_done(&mut self, symbol: Symbol, execution: &Execution) {
+        let book = &self.books[&symbol];
+        let ids = execution
+            .trades
+            .iter()
+            .flat_map(|trade| {
+                [
+                    (trade.user_id_buy, trade.user_order_id_buy),
+                    (trade.user_id_sell, trade.user_order_id_sell),
+                ]
+            })
+            .chain(
+                execution
+                    .cancelled
+                    .iter()
+                    .map(|order| (order.user_id(), order.user_order_id())),
+            );
+        let mut gone = Vec::new();
+        for ids in ids {
+            match self.indices.get(&ids) {
+                Some(index) if !book.contains(index) => {
+                    self.indices.remove(&ids);
+                    close_order(&mut self.open_orders, ids.0);
+                    gone.push(ids);
+                }
+                Some(_) => (),
+                // Orders that have never been indexed, e.g. filled right away, are gone as well.
+                None => gone.push(ids),
+            }
+        }
+        for ids in gone {
+            self.ledger.release(ids);
+        }
     }
 
     /// Clear the market of all orders.
@@ -98,31 +976,1259 @@ impl<M: Matcher> Market<M> {
             book.clear();
         }
         self.indices.clear();
+        self.open_orders.clear();
+        self.groups.clear();
+        self.ledger.release_all();
+        if let Some(used_ids) = &mut self.used_ids {
+            used_ids.clear();
+        }
+    }
+
+    /// Take all the events produced by the market since the last call.
+    pub fn take_events(&mut self) -> Vec<SequencedEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Number the event in the global sequence, stamp it with the time and publish it.
+    fn publish(&mut self, event: Event) {
+        self.sequence += 1;
+        self.events.push(SequencedEvent {
+            sequence: self.sequence,
+            timestamp: self.clock.now(),
+            event,
+        });
+    }
+
+    fn publish_order(&mut self, index: &Index) {
+        self.publish(Event::Accepted {
+            user_id: index.user_id,
+            user_order_id: index.user_order_id,
+            order_id: index.order_id,
+        });
     }
 
-    pub fn log_order(&self, index: &Index) {
-        println!("A, {}, {}", index.user_id, index.user_order_id,);
+    fn publish_reject(&mut self, index: &Index, reason: RejectReason) {
+        self.publish(Event::Rejected {
+            user_id: index.user_id,
+            user_order_id: index.user_order_id,
+            reason,
+        });
     }
 
-    pub fn log_top_of_book(&self, index: &Index) {
-        let book = self.books.get(&index.symbol).expect("Book not found");
-        let (price, quantity) = book.top_of_book(index.side);
-        if index.price == price {
-            println!("B, {}, {}, {}", index.side, price, quantity);
+    /// Publish the changes of the top of the book compared to the given one.
+    fn publish_top_of_book(&mut self, symbol: Symbol, before: TopOfBook) {
+        let after = top_of_book(&self.books[&symbol]);
+        for (side, before, after) in [
+            (Side::Bid, before.0, after.0),
+            (Side::Ask, before.1, after.1),
+        ] {
+            if before != after {
+                self.publish(Event::TopOfBook { side, level: after });
+            }
         }
     }
 
-    pub fn log_trades(&self, trades: &[Trade]) {
+    /// Publish the change of the indicative price of a call auction compared to the given one.
+    fn publish_indicative(&mut self, symbol: Symbol, before: Option<Equilibrium>) {
+        let book = &self.books[&symbol];
+        let after = book.indicative();
+        if book.phase() == Phase::Auction && before != after {
+            self.publish(Event::Indicative(after));
+        }
+    }
+
+    fn publish_phase(&mut self, symbol: Symbol, phase: Phase) {
+        self.publish(Event::Phase {
+            symbol: self.symbols.resolve(symbol).unwrap_or_default().to_string(),
+            phase,
+        });
+    }
+
+    /// Publish the halt of a book tripped by the circuit breaker during the execution and
+    /// schedule its reopening.
+    fn publish_halt(&mut self, symbol: Symbol, execution: &Execution) {
+        if !execution.halted {
+            return;
+        }
+        self.publish_phase(symbol, Phase::Halted);
+        let halt = self.breaker.map_or(Duration::ZERO, |breaker| breaker.halt);
+        self.books
+            .get_mut(&symbol)
+            .expect("Book not found")
+            .schedule(Phase::Auction, self.now + halt);
+    }
+
+    fn publish_trades(&mut self, trades: &[Trade]) {
         for trade in trades {
-            println!(
-                "T, {}, {}, {}, {}, {}, {}",
-                trade.user_id_buy,
-                trade.user_order_id_buy,
-                trade.user_id_sell,
-                trade.user_order_id_sell,
-                trade.price.0 as u64,
-                trade.quantity
+            self.positions.record(trade);
+            self.billing.record(trade);
+            self.publish(Event::Trade(trade.clone()));
+        }
+    }
+
+    fn publish_cancelled(&mut self, orders: &[Order]) {
+        for order in orders {
+            self.publish(Event::Cancelled {
+                user_id: order.user_id(),
+                user_order_id: order.user_order_id(),
+            });
+        }
+    }
+}
+
+/// Get the book of the given security, creating it with the price band of the circuit breaker
+/// if there is none yet.
+fn book_entry(
+    books: &mut HashMap<Symbol, Book>,
+    symbol: Symbol,
+    breaker: Option<CircuitBreaker>,
+) -> &mut Book {
+    books.entry(symbol).or_insert_with(|| {
+        let mut book = Book::new();
+        book.set_band(breaker.map(|breaker| breaker.band));
+        book
+    })
+}
+
+/// Count off an order of the user that is no longer indexed.
+fn close_order(open_orders: &mut HashMap<u64, usize>, user_id: u64) {
+    if let Entry::Occupied(mut count) = open_orders.entry(user_id) {
+        *count.get_mut() -= 1;
+        if *count.get() == 0 {
+            count.remove();
+        }
+    }
+}
+
+/// Best bid and ask levels of a book.
+type TopOfBook = (Option<(Price, u64)>, Option<(Price, u64)>);
+
+fn top_of_book(book: &Book) -> TopOfBook {
+    (book.top_of_book(Side::Bid), book.top_of_book(Side::Ask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group::OcoCancel;
+    use crate::matcher::FIFOMatcher;
+    use crate::order::{Peg, PegReference, PostOnly};
+
+    fn populated_market() -> Market<FIFOMatcher> {
+        let mut market = Market::new(FIFOMatcher);
+        let aapl = market.intern("AAPL");
+        let goog = market.intern("GOOG");
+        let orders = [
+            Order::with_ids(1, 101).limit_order(Side::Bid, aapl, 10.0, 100),
+            Order::with_ids(2, 201).limit_order(Side::Bid, aapl, 10.0, 50),
+            Order::with_ids(3, 301).limit_order(Side::Bid, aapl, 9.0, 70),
+            Order::with_ids(1, 102).limit_order(Side::Ask, aapl, 12.0, 30),
+            Order::with_ids(2, 202).limit_order(Side::Ask, aapl, 11.0, 20),
+            Order::with_ids(3, 302).limit_order(Side::Ask, goog, 50.0, 10),
+            Order::with_ids(1, 103).limit_order(Side::Bid, goog, 49.0, 40),
+            Order::with_ids(4, 401).limit_order(Side::Bid, aapl, 11.0, 5),
+        ];
+        for order in orders {
+            market.add(order);
+        }
+        market.cancel(3, 301);
+        market.add_oco(
+            Order::with_ids(5, 501).limit_order(Side::Ask, goog, 55.0, 10),
+            Order::with_ids(5, 502).stop_order(Side::Ask, goog, 45.0, 10),
+            OcoCancel::Fill,
+        );
+        market.take_events();
+        market
+    }
+
+    fn l3(market: &Market<FIFOMatcher>, symbol: Symbol, side: Side) -> Vec<(Price, u64, u64, u64)> {
+        market
+            .orders(symbol, side)
+            .map(|order| {
+                (
+                    order.price(),
+                    order.user_id(),
+                    order.user_order_id(),
+                    order.quantity(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let market = populated_market();
+        let snapshot = market.snapshot(42);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored_snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored_snapshot, snapshot);
+
+        let restored = Market::restore(FIFOMatcher, restored_snapshot);
+        let aapl = restored.symbols.get("AAPL").unwrap();
+        let goog = restored.symbols.get("GOOG").unwrap();
+        assert_eq!(restored.symbol_name(aapl), Some("AAPL"));
+        assert_eq!(restored.symbol_name(goog), Some("GOOG"));
+        for symbol in [aapl, goog] {
+            for side in [Side::Bid, Side::Ask] {
+                assert_eq!(l3(&restored, symbol, side), l3(&market, symbol, side));
+            }
+        }
+        assert_eq!(
+            l3(&restored, aapl, Side::Bid),
+            vec![(10.0.into(), 1, 101, 100), (10.0.into(), 2, 201, 50),]
+        );
+        assert_eq!(
+            l3(&restored, aapl, Side::Ask),
+            vec![(11.0.into(), 2, 202, 15), (12.0.into(), 1, 102, 30)]
+        );
+        assert_eq!(restored.indices, market.indices);
+        assert_eq!(restored.open_orders, market.open_orders);
+        assert_eq!(restored.groups, market.groups);
+        assert_eq!(restored.snapshot(42), snapshot);
+    }
+
+    #[test]
+    fn test_restored_market_keeps_trading() {
+        let mut market = populated_market();
+        let mut restored = Market::restore(FIFOMatcher, market.snapshot(0));
+        let aapl = restored.intern("AAPL");
+        let order = Order::with_ids(6, 601).limit_order(Side::Ask, aapl, 10.0, 120);
+        let trades = restored.add(order.clone());
+        let expected = market.add(order);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(format!("{:?}", trades), format!("{:?}", expected));
+        assert_eq!(restored.snapshot(0), market.snapshot(0));
+    }
+
+    fn output(market: &mut Market<FIFOMatcher>) -> Vec<String> {
+        market
+            .take_events()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_reject_crossing_order_without_trading() {
+        let mut market = Market::new(FIFOMatcher);
+        market.set_trading(false);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        market.add(Order::with_ids(2, 2).limit_order(Side::Ask, ibm, 11.0, 100));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 1, 1", "B, B, 10, 100", "A, 2, 2", "B, S, 11, 100"]
+        );
+
+        let trades = market.add(Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 11.0, 10));
+        assert!(trades.is_empty());
+        assert_eq!(output(&mut market), vec!["R, 1, 3"]);
+    }
+
+    #[test]
+    fn test_cancel_publishes_top_of_book() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Bid, ibm, 10.0, 50));
+        market.take_events();
+
+        assert!(market.cancel(1, 1).is_some());
+        assert_eq!(output(&mut market), vec!["A, 1, 1", "B, B, 10, 50"]);
+        assert!(market.cancel(1, 2).is_some());
+        assert_eq!(output(&mut market), vec!["A, 1, 2", "B, B, -, -"]);
+        assert!(market.cancel(1, 2).is_none());
+        assert_eq!(output(&mut market), vec!["R, 1, 2"]);
+    }
+
+    #[test]
+    fn test_mass_cancel() {
+        let mut market = populated_market();
+        let aapl = market.intern("AAPL");
+        market.add(Order::with_ids(1, 10).limit_order(Side::Bid, aapl, 1.0, 10));
+        market.add(Order::with_ids(1, 11).limit_order(Side::Ask, aapl, 50.0, 10));
+        market.add(Order::with_ids(2, 10).limit_order(Side::Ask, aapl, 60.0, 10));
+        market.take_events();
+        let count = |market: &Market<FIFOMatcher>, user_id| {
+            market
+                .indices
+                .keys()
+                .filter(|(id, _)| *id == user_id)
+                .count()
+        };
+        let before = (count(&market, 1), count(&market, 2));
+
+        let cancelled = market.mass_cancel(MassCancel {
+            user_id: Some(1),
+            symbol: Some(aapl),
+            side: Some(Side::Ask),
+        });
+        let ids = cancelled
+            .iter()
+            .map(Order::user_order_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![11, 102]);
+        assert_eq!(output(&mut market)[0], "C, 1, 11");
+        assert_eq!(count(&market, 1), before.0 - 2);
+
+        market.mass_cancel(MassCancel {
+            user_id: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(count(&market, 1), 0);
+        assert_eq!(count(&market, 2), before.1);
+        assert!(output(&mut market)
+            .iter()
+            .filter(|line| line.starts_with('C'))
+            .all(|line| line.starts_with("C, 1, ")));
+
+        market.set_phase(aapl, Phase::Closed);
+        market.take_events();
+        let cancelled = market.mass_cancel(MassCancel::default());
+        assert!(cancelled.iter().all(|order| order.symbol() != aapl));
+        assert_eq!(output(&mut market).first().unwrap(), "R, 2, 10");
+        assert_eq!(market.indices.len(), 3);
+    }
+
+    #[test]
+    fn test_halt_and_kill_switch() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Bid, ibm, 9.0, 100));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 8.0, 100));
+        market.take_events();
+
+        market.set_halted(true);
+        market.set_halted(true);
+        market.add(Order::with_ids(2, 2).limit_order(Side::Ask, ibm, 10.0, 100));
+        assert!(market.cancel(2, 1).is_some());
+        assert_eq!(output(&mut market), vec!["H, halted", "R, 2, 2", "A, 2, 1"]);
+        market.set_halted(false);
+        assert_eq!(output(&mut market), vec!["H, resumed"]);
+
+        let cancelled = market.disable_user(1);
+        assert_eq!(cancelled.len(), 2);
+        let lines = output(&mut market);
+        let cancels = lines.iter().filter(|line| line.starts_with('C'));
+        assert_eq!(cancels.collect::<Vec<_>>(), ["C, 1, 1", "C, 1, 2"]);
+        market.add(Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 10.0, 100));
+        assert!(matches!(
+            market.take_events()[0].event,
+            Event::Rejected {
+                reason: RejectReason::UserDisabled,
+                ..
+            }
+        ));
+
+        let mut market = Market::restore(FIFOMatcher, market.snapshot(0));
+        assert!(market.is_disabled(1));
+        assert!(!market.is_halted());
+        market.enable_user(1);
+        market.add(Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 10.0, 100));
+        assert_eq!(output(&mut market)[0], "A, 1, 3");
+    }
+
+    #[test]
+    fn test_ids_sequence_and_timestamps() {
+        let clock = ManualClock::new(1_000);
+        let mut market = Market::new(FIFOMatcher);
+        market.set_clock(clock.clone());
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        // Rejected orders don't get an exchange id.
+        market.add(
+            Order::with_ids(2, 1)
+                .post_only(PostOnly::Reject)
+                .limit_order(Side::Bid, ibm, 10.0, 10),
+        );
+        market.cancel(3, 1);
+        clock.advance(500);
+        let trades = market.add(Order::with_ids(2, 2).limit_order(Side::Bid, ibm, 10.0, 40));
+        assert_eq!(trades[0].trade_id, 1);
+        assert_eq!(trades[0].timestamp, 1_500);
+        assert_eq!(trades[0].aggressor, Some(Side::Bid));
+        assert_eq!(trades[0].aggressor_order_id(), Some(2));
+        assert_eq!(trades[0].passive_order_id(), Some(1));
+
+        let events = market.take_events();
+        let sequences = events.iter().map(|event| event.sequence);
+        assert!(sequences.eq(1..=events.len() as u64));
+        assert_eq!(events.first().unwrap().timestamp, 1_000);
+        assert_eq!(events.last().unwrap().timestamp, 1_500);
+
+        // The numbering goes on where it stopped after a restore.
+        let mut market = Market::restore(FIFOMatcher, market.snapshot(0));
+        let trades = market.add(Order::with_ids(3, 1).market_order(Side::Bid, ibm, 10));
+        assert_eq!(trades[0].trade_id, 2);
+        assert_eq!(trades[0].order_id_buy, 3);
+        assert_eq!(trades[0].order_id_sell, 1);
+        market.cancel(1, 1);
+        let restored_events = market.take_events();
+        assert_eq!(restored_events[0].sequence, events.len() as u64 + 1);
+
+        // The alternate format adds the exchange ids.
+        let lines = restored_events
+            .iter()
+            .map(|event| format!("{:#}", event))
+            .filter(|line| line.starts_with(['A', 'T']))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "A, 3, 1, 3",
+                "T, 3, 1, 1, 1, 10, 10, 0, 0, 2, 3, 1, B",
+                "A, 1, 1, 1"
+            ]
+        );
+        assert_eq!(restored_events[1].to_string(), "T, 3, 1, 1, 1, 10, 10");
+    }
+
+    #[test]
+    fn test_duplicate_order_ids() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        let rejections = |market: &mut Market<FIFOMatcher>| {
+            market
+                .take_events()
+                .into_iter()
+                .filter_map(|event| match event.event {
+                    Event::Rejected {
+                        user_id,
+                        user_order_id,
+                        reason,
+                    } => Some((user_id, user_order_id, reason)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 9.0, 100));
+        assert_eq!(
+            rejections(&mut market),
+            vec![(1, 1, RejectReason::DuplicateOrderId)]
+        );
+        // A pair with a duplicate is rejected as a whole, and the original order keeps trading on
+        // its own.
+        for (first, second) in [(1, 2), (2, 1), (2, 2)] {
+            market.add_oco(
+                Order::with_ids(1, first).limit_order(Side::Ask, ibm, 20.0, 10),
+                Order::with_ids(1, second).limit_order(Side::Ask, ibm, 21.0, 10),
+                OcoCancel::PartialFill,
             );
+            assert_eq!(
+                rejections(&mut market),
+                vec![
+                    (1, first, RejectReason::DuplicateOrderId),
+                    (1, second, RejectReason::DuplicateOrderId)
+                ]
+            );
+        }
+        assert!(market.groups.is_empty());
+        assert!(!market.indices.contains_key(&(1, 2)));
+        assert!(market.cancel(1, 1).is_some());
+
+        // Ids of orders that are gone can be reused, unless that is rejected too.
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        assert!(rejections(&mut market).is_empty());
+        market.set_reject_reused_ids(true);
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        let mut market = Market::restore(FIFOMatcher, market.snapshot(0));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        assert_eq!(
+            rejections(&mut market),
+            vec![(2, 1, RejectReason::DuplicateOrderId)]
+        );
+        market.clear();
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        assert!(rejections(&mut market).is_empty());
+    }
+
+    #[test]
+    fn test_stop_orders() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        market.add(Order::with_ids(2, 1).stop_order(Side::Bid, ibm, 10.0, 150));
+        market.add(Order::with_ids(2, 2).stop_order(Side::Bid, ibm, 12.0, 10));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 1, 1", "B, S, 10, 100", "A, 2, 1", "A, 2, 2"]
+        );
+
+        assert!(market.cancel(2, 2).is_some());
+        assert_eq!(output(&mut market), vec!["A, 2, 2"]);
+
+        market.add(Order::with_ids(3, 1).limit_order(Side::Bid, ibm, 10.0, 10));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 3, 1",
+                "T, 3, 1, 1, 1, 10, 10",
+                "T, 2, 1, 1, 1, 10, 90",
+                "C, 2, 1",
+                "B, S, -, -"
+            ]
+        );
+        assert!(market.indices.is_empty());
+    }
+
+    #[test]
+    fn test_iceberg_order_displays_only_a_slice() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 12.0, 30));
+        let iceberg =
+            Order::with_ids(2, 1)
+                .display_quantity(10)
+                .limit_order(Side::Bid, ibm, 12.0, 75);
+        market.add(iceberg);
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 1, 1",
+                "B, S, 12, 30",
+                "A, 2, 1",
+                "T, 2, 1, 1, 1, 12, 30",
+                "B, B, 12, 10",
+                "B, S, -, -"
+            ]
+        );
+
+        market.add(Order::with_ids(3, 1).limit_order(Side::Ask, ibm, 12.0, 15));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 3, 1",
+                "T, 2, 1, 3, 1, 12, 10",
+                "T, 2, 1, 3, 1, 12, 5",
+                "B, B, 12, 5"
+            ]
+        );
+        assert!(market.cancel(2, 1).is_some());
+        assert_eq!(output(&mut market), vec!["A, 2, 1", "B, B, -, -"]);
+    }
+
+    #[test]
+    fn test_hidden_order_is_not_displayed() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(
+            Order::with_ids(1, 1)
+                .hidden()
+                .limit_order(Side::Ask, ibm, 11.0, 20),
+        );
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 12.0, 10));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 1, 1", "A, 2, 1", "B, S, 12, 10"]
+        );
+        assert_eq!(market.orders(ibm, Side::Ask).count(), 1);
+
+        market.add(Order::with_ids(3, 1).limit_order(Side::Bid, ibm, 12.0, 25));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 3, 1",
+                "T, 3, 1, 1, 1, 11, 20",
+                "T, 3, 1, 2, 1, 12, 5",
+                "B, S, 12, 5"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_call_auction() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        assert!(market.set_phase(ibm, Phase::Auction).is_some());
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 12.0, 10));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 11.0, 4));
+        market.add(Order::with_ids(3, 1).market_order(Side::Ask, ibm, 5));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "P, IBM, auction",
+                "A, 1, 1",
+                "B, B, 12, 10",
+                "A, 2, 1",
+                "B, S, 11, 4",
+                "I, 11, 4, 6",
+                "R, 3, 1"
+            ]
+        );
+        assert!(market.cancel(2, 1).is_some());
+        market.add(Order::with_ids(2, 2).limit_order(Side::Ask, ibm, 12.0, 4));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 2, 1",
+                "B, S, -, -",
+                "I, -, -, -",
+                "A, 2, 2",
+                "B, S, 12, 4",
+                "I, 12, 4, 6"
+            ]
+        );
+
+        market.set_phase(ibm, Phase::Continuous);
+        market.add(Order::with_ids(4, 1).limit_order(Side::Ask, ibm, 12.0, 1));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "P, IBM, continuous",
+                "T, 1, 1, 2, 2, 12, 4",
+                "B, B, 12, 6",
+                "B, S, -, -",
+                "A, 4, 1",
+                "T, 1, 1, 4, 1, 12, 1",
+                "B, B, 12, 5"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trading_phases() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 5));
+        assert!(market.set_phase(ibm, Phase::PreOpen).is_none());
+        assert!(market.set_phase(ibm, Phase::Halted).is_some());
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 10.0, 5));
+        assert!(market.cancel(1, 1).is_some());
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 1, 1",
+                "B, B, 10, 5",
+                "P, IBM, halted",
+                "R, 2, 1",
+                "A, 1, 1",
+                "B, B, -, -"
+            ]
+        );
+
+        market.set_phase(ibm, Phase::Closed);
+        market.set_phase(ibm, Phase::PreOpen);
+        market.add(Order::with_ids(3, 1).limit_order(Side::Bid, ibm, 11.0, 5));
+        market.add(Order::with_ids(4, 1).limit_order(Side::Ask, ibm, 10.0, 3));
+        market.set_phase(ibm, Phase::Closed);
+        assert!(market.cancel(3, 1).is_none());
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "P, IBM, closed",
+                "P, IBM, pre-open",
+                "A, 3, 1",
+                "B, B, 11, 5",
+                "A, 4, 1",
+                "B, S, 10, 3",
+                "P, IBM, closed",
+                "R, 3, 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_halts_and_reopens() {
+        let mut market = Market::new(FIFOMatcher);
+        market.set_circuit_breaker(Some(CircuitBreaker {
+            band: 0.1,
+            halt: Duration::from_secs(10),
+            reopening: Duration::from_secs(2),
+        }));
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 100.0, 5));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 105.0, 5));
+        market.add(Order::with_ids(3, 1).limit_order(Side::Ask, ibm, 120.0, 5));
+        market.add(Order::with_ids(4, 1).limit_order(Side::Bid, ibm, 100.0, 5));
+        output(&mut market);
+
+        market.add(Order::with_ids(5, 1).limit_order(Side::Bid, ibm, 120.0, 10));
+        market.add(Order::with_ids(6, 1).limit_order(Side::Bid, ibm, 90.0, 10));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 5, 1",
+                "T, 5, 1, 2, 1, 105, 5",
+                "B, B, 120, 5",
+                "B, S, 120, 5",
+                "P, IBM, halted",
+                "R, 6, 1"
+            ]
+        );
+
+        // A market advanced straight past the reopening goes through the same changes.
+        let mut skipped = Market::restore(FIFOMatcher, market.snapshot(0));
+        skipped.set_circuit_breaker(market.breaker);
+
+        market.advance(Duration::from_secs(9));
+        assert!(output(&mut market).is_empty());
+        market.advance(Duration::from_secs(10));
+        assert_eq!(output(&mut market), vec!["P, IBM, auction", "I, 120, 5, 0"]);
+        market.advance(Duration::from_secs(12));
+        let reopening = vec![
+            "P, IBM, continuous",
+            "T, 5, 1, 3, 1, 120, 5",
+            "B, B, -, -",
+            "B, S, -, -",
+        ];
+        assert_eq!(output(&mut market), reopening);
+
+        skipped.advance(Duration::from_secs(15));
+        let mut steps = vec!["P, IBM, auction", "I, 120, 5, 0"];
+        steps.extend(reopening);
+        assert_eq!(output(&mut skipped), steps);
+    }
+
+    #[test]
+    fn test_risk_limits() {
+        let mut market = Market::new(FIFOMatcher);
+        market.set_default_risk_limits(RiskLimits {
+            max_quantity: Some(100),
+            max_price_deviation: Some(0.25),
+            ..Default::default()
+        });
+        market.set_risk_limits(
+            2,
+            RiskLimits {
+                max_open_orders: Some(1),
+                max_position: Some(10),
+                ..Default::default()
+            },
+        );
+        market.set_risk_limits(
+            3,
+            RiskLimits {
+                max_notional: Some(500.0),
+                ..Default::default()
+            },
+        );
+        let ibm = market.intern("IBM");
+        let orders = [
+            Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 150),
+            Order::with_ids(1, 2).limit_order(Side::Ask, ibm, 10.0, 90),
+            Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 5.0, 10),
+            Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 5),
+            Order::with_ids(2, 2).limit_order(Side::Bid, ibm, 10.0, 6),
+            Order::with_ids(2, 3).limit_order(Side::Bid, ibm, 8.0, 4),
+            Order::with_ids(2, 4).limit_order(Side::Bid, ibm, 8.0, 1),
+            Order::with_ids(3, 1).limit_order(Side::Bid, ibm, 10.0, 60),
+            Order::with_ids(3, 2).market_order(Side::Bid, ibm, 40),
+        ];
+        for order in orders {
+            market.add(order);
+        }
+        let rejects = market
+            .take_events()
+            .into_iter()
+            .filter_map(|event| match event.event {
+                Event::Rejected {
+                    user_id,
+                    user_order_id,
+                    reason,
+                } => Some((user_id, user_order_id, reason)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejects,
+            vec![
+                (1, 1, RejectReason::QuantityLimit),
+                (1, 3, RejectReason::PriceDeviation),
+                (2, 2, RejectReason::PositionLimit),
+                (2, 4, RejectReason::OpenOrderLimit),
+                (3, 1, RejectReason::NotionalLimit)
+            ]
+        );
+        assert_eq!(market.position(2, ibm), 5);
+        assert_eq!(market.position(3, ibm), 40);
+        assert_eq!(market.position(1, ibm), -45);
+
+        // Cancelling the open order makes room for another one.
+        assert!(market.cancel(2, 3).is_some());
+        market.add(Order::with_ids(2, 5).limit_order(Side::Bid, ibm, 8.0, 1));
+        assert!(market.indices.contains_key(&(2, 5)));
+        assert_eq!(market.open_orders[&2], 1);
+    }
+
+    #[test]
+    fn test_risk_limits_at_posted_prices() {
+        let mut market = Market::new(FIFOMatcher);
+        market.set_default_risk_limits(RiskLimits {
+            max_notional: Some(500.0),
+            max_price_deviation: Some(0.25),
+            ..Default::default()
+        });
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 40));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Bid, ibm, 9.0, 40));
+        market.take_events();
+
+        // Valued at the peg price of 9 rather than the best ask.
+        market.add(Order::with_ids(2, 1).pegged_order(
+            Side::Bid,
+            ibm,
+            peg(PegReference::Primary, 0.0),
+            52,
+        ));
+        market.add(Order::with_ids(2, 2).pegged_order(
+            Side::Bid,
+            ibm,
+            peg(PegReference::Market, -5.0),
+            10,
+        ));
+        // Valued at the repriced 9 rather than 12.
+        market.add(
+            Order::with_ids(2, 3)
+                .post_only(PostOnly::Reprice)
+                .limit_order(Side::Bid, ibm, 12.0, 55),
+        );
+        let lines = output(&mut market);
+        let orders = lines.iter().filter(|line| line.starts_with(['A', 'R']));
+        assert_eq!(
+            orders.collect::<Vec<_>>(),
+            ["A, 2, 1", "R, 2, 2", "A, 2, 3"]
+        );
+    }
+
+    #[test]
+    fn test_position_reports() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        let aapl = market.intern("AAPL");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Ask, aapl, 20.0, 100));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 60));
+        market.add(Order::with_ids(2, 2).limit_order(Side::Bid, aapl, 20.0, 10));
+        market.add(Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 8.0, 10));
+        market.add(Order::with_ids(3, 1).limit_order(Side::Bid, aapl, 21.0, 90));
+        market.add(Order::with_ids(3, 2).limit_order(Side::Ask, aapl, 25.0, 30));
+
+        // IBM is marked to the midpoint and AAPL, with no bids left, to the last trade price.
+        let reports = market.position_reports(1);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].symbol, aapl);
+        assert_eq!(reports[0].position.quantity, -100);
+        assert_eq!(reports[0].mark_price, Some(20.0.into()));
+        assert_eq!(reports[0].unrealized_pnl, 0.0);
+        assert_eq!(reports[1].symbol, ibm);
+        assert_eq!(reports[1].position.quantity, -60);
+        assert_eq!(reports[1].mark_price, Some(9.0.into()));
+        assert_eq!(reports[1].unrealized_pnl, 60.0);
+
+        market.add(Order::with_ids(2, 3).limit_order(Side::Bid, aapl, 25.0, 50));
+        let reports = market.position_reports(3);
+        assert_eq!(reports[0].position.quantity, 60);
+        assert_eq!(reports[0].position.average_cost, 20.0);
+        assert_eq!(reports[0].position.realized_pnl, 150.0);
+        assert!(market.position_reports(4).is_empty());
+
+        let restored = Market::restore(FIFOMatcher, market.snapshot(0));
+        assert_eq!(restored.position_reports(1), market.position_reports(1));
+    }
+
+    #[test]
+    fn test_funded_orders() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        let usd = market.intern("USD");
+        market.set_funding(Some(usd));
+        market.deposit(1, usd, 1000.0);
+        market.deposit(2, ibm, 100.0);
+        let balance = |available, reserved| Balance {
+            available,
+            reserved,
+        };
+
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 50));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Bid, ibm, 10.0, 60));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 9.0, 150));
+        market.add(Order::with_ids(2, 2).limit_order(Side::Ask, ibm, 10.0, 30));
+        assert_eq!(market.balance(1, usd), balance(500.0, 200.0));
+        assert_eq!(market.balance(1, ibm), balance(30.0, 0.0));
+        assert_eq!(market.balance(2, usd), balance(300.0, 0.0));
+        assert_eq!(market.balance(2, ibm), balance(70.0, 0.0));
+
+        // Cancelling releases what is left of the reservation, and so does a fill at a better
+        // price than the limit.
+        market.cancel(1, 1);
+        assert_eq!(market.balance(1, usd), balance(700.0, 0.0));
+        market.add(Order::with_ids(2, 3).limit_order(Side::Ask, ibm, 8.0, 10));
+        assert_eq!(market.balance(2, ibm), balance(60.0, 10.0));
+        market.add(Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 12.0, 10));
+        assert_eq!(market.balance(1, usd), balance(620.0, 0.0));
+        assert_eq!(market.balance(2, ibm), balance(60.0, 0.0));
+        market.add(Order::with_ids(1, 4).stop_order(Side::Bid, ibm, 15.0, 10));
+
+        let rejects = market
+            .take_events()
+            .into_iter()
+            .filter_map(|event| match event.event {
+                Event::Rejected {
+                    user_id,
+                    user_order_id,
+                    reason,
+                } => Some((user_id, user_order_id, reason)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejects,
+            vec![
+                (1, 2, RejectReason::InsufficientFunds),
+                (2, 1, RejectReason::InsufficientFunds),
+                (1, 4, RejectReason::UnknownCost)
+            ]
+        );
+        assert!(market.audit_ledger());
+        assert!(market
+            .ledger_entries()
+            .iter()
+            .all(|entry| entry.debit != entry.credit && entry.amount > 0.0));
+
+        let mut restored = Market::restore(FIFOMatcher, market.snapshot(0));
+        assert!(restored.audit_ledger());
+        assert_eq!(restored.balance(1, usd), market.balance(1, usd));
+        // The numbering of the entries carries on after the restore.
+        let last = market.take_ledger_entries().last().unwrap().sequence;
+        assert!(market.ledger_entries().is_empty());
+        restored.deposit(1, usd, 1.0);
+        let entries = restored.take_ledger_entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sequence, last + 1);
+        assert_eq!(entries[0].debit.to_string(), "available:1");
+        assert_eq!(entries[0].credit.to_string(), "external");
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        let aapl = market.intern("AAPL");
+        market.set_default_fee_rates(FeeRates {
+            maker: 0.001,
+            taker: 0.002,
+        });
+        market.set_fee_rates(
+            None,
+            1,
+            FeeRates {
+                maker: -0.001,
+                taker: 0.001,
+            },
+        );
+        market.set_fee_rates(
+            Some(aapl),
+            1,
+            FeeRates {
+                maker: 0.0,
+                taker: 0.0,
+            },
+        );
+        market.set_fee_tier(1, 1);
+
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        market.add(Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 50));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Ask, aapl, 20.0, 100));
+        market.add(Order::with_ids(2, 2).limit_order(Side::Bid, aapl, 20.0, 50));
+        let output = output(&mut market);
+        assert!(output.contains(&"T, 2, 1, 1, 1, 10, 50, 1, -0.5".to_string()));
+        assert!(output.contains(&"T, 2, 2, 1, 2, 20, 50, 2, 0".to_string()));
+
+        let totals = market.fee_totals();
+        let total = |quantity, value, fees, rebates| FeeTotal {
+            quantity,
+            value,
+            fees,
+            rebates,
+        };
+        assert_eq!(
+            totals,
+            vec![
+                (1, aapl, total(50, 1000.0, 0.0, 0.0)),
+                (1, ibm, total(50, 500.0, 0.0, 0.5)),
+                (2, aapl, total(50, 1000.0, 2.0, 0.0)),
+                (2, ibm, total(50, 500.0, 1.0, 0.0)),
+            ]
+        );
+        let restored = Market::restore(FIFOMatcher, market.snapshot(0));
+        assert_eq!(restored.fee_totals(), totals);
+    }
+
+    #[test]
+    fn test_post_only_orders() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 12.0, 30));
+        market.take_events();
+
+        let order = Order::with_ids(2, 1)
+            .post_only(PostOnly::Reject)
+            .limit_order(Side::Bid, ibm, 12.0, 10);
+        assert!(market.add(order).is_empty());
+        assert_eq!(output(&mut market), vec!["R, 2, 1"]);
+
+        let order = Order::with_ids(2, 2)
+            .post_only(PostOnly::Reprice)
+            .limit_order(Side::Bid, ibm, 13.0, 10);
+        assert!(market.add(order).is_empty());
+        assert_eq!(output(&mut market), vec!["A, 2, 2", "B, B, 11, 10"]);
+
+        // A post-only order that doesn't cross is added as it is.
+        let order = Order::with_ids(2, 3)
+            .post_only(PostOnly::Reject)
+            .limit_order(Side::Ask, ibm, 12.0, 10);
+        assert!(market.add(order).is_empty());
+        assert_eq!(output(&mut market), vec!["A, 2, 3", "B, S, 12, 40"]);
+
+        // The repriced order is found at its new price.
+        assert!(market.cancel(2, 2).is_some());
+        assert_eq!(output(&mut market), vec!["A, 2, 2", "B, B, -, -"]);
+    }
+
+    fn peg(reference: PegReference, offset: f64) -> Peg {
+        Peg {
+            reference,
+            offset: offset.into(),
+            limit: None,
         }
     }
+
+    #[test]
+    fn test_primary_peg_follows_best_price() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        market.add(Order::with_ids(1, 2).limit_order(Side::Ask, ibm, 13.0, 100));
+        let order =
+            Order::with_ids(2, 1).pegged_order(Side::Bid, ibm, peg(PegReference::Primary, 0.0), 10);
+        market.add(order);
+        market.take_events();
+
+        market.add(Order::with_ids(1, 3).limit_order(Side::Bid, ibm, 11.0, 100));
+        assert_eq!(output(&mut market), vec!["A, 1, 3", "B, B, 11, 110"]);
+        assert_eq!(
+            l3(&market, ibm, Side::Bid),
+            vec![
+                (11.0.into(), 1, 3, 100),
+                (11.0.into(), 2, 1, 10),
+                (10.0.into(), 1, 1, 100)
+            ]
+        );
+
+        assert!(market.cancel(1, 3).is_some());
+        assert_eq!(output(&mut market), vec!["A, 1, 3", "B, B, 10, 110"]);
+        assert!(market.cancel(2, 1).is_some());
+        assert_eq!(output(&mut market), vec!["A, 2, 1", "B, B, 10, 100"]);
+    }
+
+    #[test]
+    fn test_market_peg_never_crosses() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 13.0, 100));
+        let order =
+            Order::with_ids(2, 1).pegged_order(Side::Bid, ibm, peg(PegReference::Market, 0.0), 10);
+        assert!(market.add(order).is_empty());
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 1, 1", "B, S, 13, 100", "A, 2, 1", "B, B, 12, 10"]
+        );
+    }
+
+    #[test]
+    fn test_midpoint_pegs_match_at_midpoint() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        let order = Order::with_ids(2, 1).pegged_order(
+            Side::Bid,
+            ibm,
+            peg(PegReference::Midpoint, 0.0),
+            10,
+        );
+        // Without both sides of the book there is no midpoint, so the order is parked.
+        market.add(order);
+        market.add(Order::with_ids(1, 1).limit_order(Side::Bid, ibm, 10.0, 100));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 2, 1", "A, 1, 1", "B, B, 10, 100"]
+        );
+        market.add(Order::with_ids(1, 2).limit_order(Side::Ask, ibm, 14.0, 100));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 1, 2", "B, B, 12, 10", "B, S, 14, 100"]
+        );
+
+        let order =
+            Order::with_ids(3, 1).pegged_order(Side::Ask, ibm, peg(PegReference::Midpoint, 0.0), 4);
+        let trades = market.add(order);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, 12.0);
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 3, 1", "T, 2, 1, 3, 1, 12, 4", "B, B, 12, 6"]
+        );
+
+        // Moving the midpoint repositions the remaining pegged bid.
+        market.add(Order::with_ids(1, 3).limit_order(Side::Ask, ibm, 13.0, 100));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 1, 3", "B, B, 11.5, 6", "B, S, 13, 100"]
+        );
+
+        // Trades at a fractional midpoint are published at that very price.
+        let order =
+            Order::with_ids(4, 1).pegged_order(Side::Ask, ibm, peg(PegReference::Midpoint, 0.0), 2);
+        market.add(order);
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 4, 1", "T, 2, 1, 4, 1, 11.5, 2", "B, B, 11.5, 4"]
+        );
+    }
+
+    #[test]
+    fn test_oco_partial_fill_cancels_other() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add_oco(
+            Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 50),
+            Order::with_ids(2, 2).limit_order(Side::Bid, ibm, 9.0, 50),
+            OcoCancel::PartialFill,
+        );
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 2, 1", "B, B, 10, 50", "A, 2, 2"]
+        );
+
+        market.add(Order::with_ids(3, 1).limit_order(Side::Ask, ibm, 10.0, 20));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 3, 1",
+                "T, 2, 1, 3, 1, 10, 20",
+                "B, B, 10, 30",
+                "C, 2, 2"
+            ]
+        );
+        assert!(market.groups.is_empty());
+        assert!(market.cancel(2, 2).is_none());
+    }
+
+    #[test]
+    fn test_oco_fill_cancels_other() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add_oco(
+            Order::with_ids(2, 1).limit_order(Side::Ask, ibm, 12.0, 50),
+            Order::with_ids(2, 2).stop_order(Side::Ask, ibm, 8.0, 50),
+            OcoCancel::Fill,
+        );
+        market.add(Order::with_ids(3, 1).limit_order(Side::Bid, ibm, 12.0, 20));
+        market.take_events();
+
+        market.add(Order::with_ids(3, 2).limit_order(Side::Bid, ibm, 12.0, 30));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 3, 2", "T, 3, 2, 2, 1, 12, 30", "B, S, -, -", "C, 2, 2"]
+        );
+        assert!(market.indices.is_empty());
+        assert!(market.groups.is_empty());
+    }
+
+    #[test]
+    fn test_oco_first_order_filled_immediately() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add(Order::with_ids(1, 1).limit_order(Side::Ask, ibm, 10.0, 100));
+        market.take_events();
+        market.add_oco(
+            Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 50),
+            Order::with_ids(2, 2).limit_order(Side::Bid, ibm, 9.0, 50),
+            OcoCancel::Fill,
+        );
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 2, 1",
+                "T, 2, 1, 1, 1, 10, 50",
+                "B, S, 10, 50",
+                "C, 2, 2"
+            ]
+        );
+        assert!(market.groups.is_empty());
+        assert_eq!(market.indices.len(), 1);
+    }
+
+    #[test]
+    fn test_bracket_order() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add_bracket(
+            Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 10),
+            Order::with_ids(2, 2).limit_order(Side::Ask, ibm, 12.0, 10),
+            Order::with_ids(2, 3).stop_order(Side::Ask, ibm, 8.0, 10),
+            OcoCancel::PartialFill,
+        );
+        assert_eq!(output(&mut market), vec!["A, 2, 1", "B, B, 10, 10"]);
+
+        // The children are only activated once the entry is completely filled.
+        market.add(Order::with_ids(3, 1).limit_order(Side::Ask, ibm, 10.0, 4));
+        market.take_events();
+        market.add(Order::with_ids(3, 2).limit_order(Side::Ask, ibm, 10.0, 6));
+        assert_eq!(
+            output(&mut market),
+            vec![
+                "A, 3, 2",
+                "T, 2, 1, 3, 2, 10, 6",
+                "B, B, -, -",
+                "A, 2, 2",
+                "B, S, 12, 10",
+                "A, 2, 3"
+            ]
+        );
+
+        market.add(Order::with_ids(4, 1).limit_order(Side::Bid, ibm, 12.0, 4));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 4, 1", "T, 4, 1, 2, 2, 12, 4", "B, S, 12, 6", "C, 2, 3"]
+        );
+        assert!(market.groups.is_empty());
+    }
+
+    #[test]
+    fn test_bracket_order_cancelled_on_fill() {
+        let mut market = Market::new(FIFOMatcher);
+        let ibm = market.intern("IBM");
+        market.add_bracket(
+            Order::with_ids(2, 1).limit_order(Side::Bid, ibm, 10.0, 10),
+            Order::with_ids(2, 2).limit_order(Side::Ask, ibm, 12.0, 10),
+            Order::with_ids(2, 3).stop_order(Side::Ask, ibm, 8.0, 10),
+            OcoCancel::Fill,
+        );
+        market.add(Order::with_ids(3, 1).limit_order(Side::Ask, ibm, 10.0, 10));
+        market.take_events();
+
+        // A partial fill of the take-profit order leaves the stop-loss order on the market.
+        market.add(Order::with_ids(4, 1).limit_order(Side::Bid, ibm, 12.0, 4));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 4, 1", "T, 4, 1, 2, 2, 12, 4", "B, S, 12, 6"]
+        );
+        assert_eq!(
+            market.groups.get(&(2, 3)),
+            Some(&Link::Oco {
+                other: (2, 2),
+                cancel_on: OcoCancel::Fill,
+            })
+        );
+
+        market.add(Order::with_ids(4, 2).limit_order(Side::Bid, ibm, 12.0, 6));
+        assert_eq!(
+            output(&mut market),
+            vec!["A, 4, 2", "T, 4, 2, 2, 2, 12, 6", "B, S, -, -", "C, 2, 3"]
+        );
+        assert!(market.groups.is_empty());
+    }
 }
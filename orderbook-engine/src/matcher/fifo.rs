@@ -5,13 +5,24 @@ pub struct FIFOMatcher;
 impl Matcher for FIFOMatcher {
     fn match_order(&mut self, order: &mut Order, level: &mut Level) -> Vec<Trade> {
         let mut trades = Vec::new();
-        while let Some(other) = level.orders_mut().back_mut() {
-            trades.push(order.match_to(other));
-            if other.is_done() {
-                level.orders_mut().pop_back();
+        // Orders are matched from the back of the queue, where the oldest order is.
+        let mut position = level.orders().len();
+        while position > 0 && !order.is_done() {
+            position -= 1;
+            let other = &mut level.orders_mut()[position];
+            // All-or-none orders that can't be filled completely keep their place in the queue.
+            if other.is_all_or_none() && other.remaining_quantity() > order.quantity() {
+                continue;
             }
-            if order.is_done() {
-                break;
+            trades.push(order.match_to(other));
+            if other.quantity() == 0 {
+                let mut other = level.orders_mut().remove(position).unwrap();
+                // Iceberg orders get a new displayed slice at the back of the queue, which
+                // shifts the orders still to be matched by one.
+                if other.replenish() {
+                    level.add(other);
+                    position += 1;
+                }
             }
         }
         trades
@@ -230,4 +241,109 @@ mod tests {
         assert_eq!(level.orders().len(), 0);
         assert_eq!(bid_order.quantity(), 3);
     }
+
+    #[test]
+    fn test_iceberg_order_loses_priority() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut matcher = FIFOMatcher;
+        let mut iceberg =
+            Order::with_ids(1, 101)
+                .display_quantity(5)
+                .limit_order(Side::Ask, aapl, 1.0, 12);
+        iceberg.hide_reserve();
+        let mut level = Level::new(1.0.into(), Side::Ask);
+        level.add(iceberg);
+        level.add(Order::with_ids(2, 102).limit_order(Side::Ask, aapl, 1.0, 10));
+        assert_eq!(level.quantity(), 15);
+
+        let mut bid_order = Order::with_ids(4, 51).limit_order(Side::Bid, aapl, 1.0, 8);
+        let trades = matcher.match_order(&mut bid_order, &mut level);
+
+        // The displayed part of the iceberg is filled first, then the next order in the queue
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].quantity, 5);
+        assert_eq!(trades[0].user_order_id_sell, 101);
+        assert_eq!(trades[1].quantity, 3);
+        assert_eq!(trades[1].user_order_id_sell, 102);
+
+        // The replenished iceberg is at the back of the queue
+        assert_eq!(level.orders().len(), 2);
+        assert_eq!(level.orders()[0].user_order_id(), 101);
+        assert_eq!(level.orders()[0].quantity(), 5);
+        assert_eq!(level.orders()[1].quantity(), 7);
+        assert!(bid_order.is_done());
+
+        // The last slice is only what is left in the reserve
+        let mut bid_order = Order::with_ids(4, 52).limit_order(Side::Bid, aapl, 1.0, 20);
+        let trades = matcher.match_order(&mut bid_order, &mut level);
+        let quantities = trades
+            .iter()
+            .map(|trade| (trade.user_order_id_sell, trade.quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(quantities, vec![(102, 7), (101, 5), (101, 2)]);
+        assert!(level.is_empty());
+        assert_eq!(bid_order.quantity(), 6);
+    }
+
+    #[test]
+    fn test_all_or_none_order_keeps_position() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut matcher = FIFOMatcher;
+        let mut level = Level::new(1.0.into(), Side::Ask);
+        level.add(
+            Order::with_ids(1, 101)
+                .all_or_none()
+                .limit_order(Side::Ask, aapl, 1.0, 10),
+        );
+        level.add(Order::with_ids(2, 102).limit_order(Side::Ask, aapl, 1.0, 5));
+        level.add(Order::with_ids(3, 103).limit_order(Side::Ask, aapl, 1.0, 7));
+
+        // The all-or-none order is too large for the incoming order, so it is skipped
+        let mut bid_order = Order::with_ids(4, 51).limit_order(Side::Bid, aapl, 1.0, 8);
+        let trades = matcher.match_order(&mut bid_order, &mut level);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].user_order_id_sell, 102);
+        assert_eq!(trades[0].quantity, 5);
+        assert_eq!(trades[1].user_order_id_sell, 103);
+        assert_eq!(trades[1].quantity, 3);
+        assert_eq!(level.orders().len(), 2);
+        assert_eq!(level.orders()[1].user_order_id(), 101);
+
+        // but it is still first in the queue for an order that can fill it
+        let mut bid_order = Order::with_ids(4, 52).limit_order(Side::Bid, aapl, 1.0, 12);
+        let trades = matcher.match_order(&mut bid_order, &mut level);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].user_order_id_sell, 101);
+        assert_eq!(trades[0].quantity, 10);
+        assert_eq!(trades[1].user_order_id_sell, 103);
+        assert_eq!(trades[1].quantity, 2);
+        assert_eq!(level.orders()[0].quantity(), 2);
+    }
+
+    #[test]
+    fn test_hidden_order_yields_to_displayed_orders() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut matcher = FIFOMatcher;
+        let mut level = Level::new(1.0.into(), Side::Ask);
+        level.add(
+            Order::with_ids(1, 101)
+                .hidden()
+                .limit_order(Side::Ask, aapl, 1.0, 5),
+        );
+        level.add(Order::with_ids(2, 102).limit_order(Side::Ask, aapl, 1.0, 10));
+        assert_eq!(level.quantity(), 10);
+
+        let mut bid_order = Order::with_ids(4, 51).limit_order(Side::Bid, aapl, 1.0, 12);
+        let trades = matcher.match_order(&mut bid_order, &mut level);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].quantity, 10);
+        assert_eq!(trades[0].user_id_sell, 2);
+        assert_eq!(trades[1].quantity, 2);
+        assert_eq!(trades[1].user_id_sell, 1);
+        assert_eq!(level.orders().len(), 1);
+        assert_eq!(level.quantity(), 0);
+    }
 }
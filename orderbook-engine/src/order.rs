@@ -1,15 +1,29 @@
 //! This module contains the definition of all order structs that are used in the orderbook engine.
 
+use crate::fee::FeeRates;
+use crate::snapshot::serde_symbol;
 use crate::trade::Trade;
 use crate::{Price, Symbol};
 
+use serde::{Deserialize, Serialize};
+
 /// Side represents the side of the order: bid or ask.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Side {
     Bid,
     Ask,
 }
 
+impl Side {
+    /// Get the other side of the market.
+    pub fn opposite(self) -> Self {
+        match self {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        }
+    }
+}
+
 impl std::fmt::Display for Side {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -19,12 +33,12 @@ impl std::fmt::Display for Side {
     }
 }
 
-/// Order enum represents all possible order types that appear on the market. At present,
-/// only limit orders are supported.
-#[derive(Debug)]
+/// Order enum represents all possible order types that appear on the market.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Order {
     Limit(LimitOrder),
-    // TODO: add market orders and possibly other order types
+    Market(MarketOrder),
+    Stop(StopOrder),
 }
 
 impl Order {
@@ -37,6 +51,8 @@ impl Order {
     pub fn user_id(&self) -> u64 {
         match self {
             Order::Limit(order) => order.user_id,
+            Order::Market(order) => order.user_id,
+            Order::Stop(order) => order.user_id,
         }
     }
 
@@ -44,6 +60,27 @@ impl Order {
     pub fn user_order_id(&self) -> u64 {
         match self {
             Order::Limit(order) => order.user_order_id,
+            Order::Market(order) => order.user_order_id,
+            Order::Stop(order) => order.user_order_id,
+        }
+    }
+
+    /// Get the id assigned to the order by the exchange once it has been accepted, or zero
+    /// before that.
+    pub fn order_id(&self) -> u64 {
+        match self {
+            Order::Limit(order) => order.order_id,
+            Order::Market(order) => order.order_id,
+            Order::Stop(order) => order.order_id,
+        }
+    }
+
+    /// Set the id assigned to the order by the exchange.
+    pub fn set_order_id(&mut self, order_id: u64) {
+        match self {
+            Order::Limit(order) => order.order_id = order_id,
+            Order::Market(order) => order.order_id = order_id,
+            Order::Stop(order) => order.order_id = order_id,
         }
     }
 
@@ -51,13 +88,21 @@ impl Order {
     pub fn symbol(&self) -> Symbol {
         match self {
             Order::Limit(order) => order.symbol,
+            Order::Market(order) => order.symbol,
+            Order::Stop(order) => order.symbol,
         }
     }
 
-    /// Get the order bid or ask price.
+    /// Get the order bid or ask price. Market orders are willing to trade at any price, so they
+    /// are priced at the worst possible price for their side. Stop orders are priced at the price
+    /// of the order they are turned into when triggered.
     pub fn price(&self) -> Price {
         match self {
             Order::Limit(order) => order.price,
+            Order::Market(order) => market_price(order.side),
+            Order::Stop(order) => order
+                .limit_price
+                .unwrap_or_else(|| market_price(order.side)),
         }
     }
 
@@ -65,36 +110,236 @@ impl Order {
     pub fn side(&self) -> Side {
         match self {
             Order::Limit(order) => order.side,
+            Order::Market(order) => order.side,
+            Order::Stop(order) => order.side,
         }
     }
 
-    /// Get the order quantity.
+    /// Get the order quantity. For iceberg orders resting in the book this is only the
+    /// displayed quantity, without the reserve.
     pub fn quantity(&self) -> u64 {
         match self {
             Order::Limit(order) => order.quantity,
+            Order::Market(order) => order.quantity,
+            Order::Stop(order) => order.quantity,
+        }
+    }
+
+    fn quantity_mut(&mut self) -> &mut u64 {
+        match self {
+            Order::Limit(order) => &mut order.quantity,
+            Order::Market(order) => &mut order.quantity,
+            Order::Stop(order) => &mut order.quantity,
+        }
+    }
+
+    /// Get the quantity that must be executed immediately for the order to trade at all when it
+    /// arrives. All-or-none orders must be filled completely.
+    pub fn min_quantity(&self) -> u64 {
+        match self {
+            Order::Limit(order) if order.all_or_none => order.quantity + order.reserve,
+            Order::Limit(order) => order.min_quantity.unwrap_or(0),
+            Order::Market(order) => order.min_quantity.unwrap_or(0),
+            Order::Stop(_) => 0,
+        }
+    }
+
+    /// Check if the order can only be filled completely, and not partially.
+    pub fn is_all_or_none(&self) -> bool {
+        matches!(self, Order::Limit(order) if order.all_or_none)
+    }
+
+    /// Check if the order is hidden from the market data.
+    pub fn is_hidden(&self) -> bool {
+        matches!(self, Order::Limit(order) if order.hidden)
+    }
+
+    /// Get the whole remaining quantity of the order, including the reserve.
+    pub fn remaining_quantity(&self) -> u64 {
+        match self {
+            Order::Limit(order) => order.quantity + order.reserve,
+            _ => self.quantity(),
+        }
+    }
+
+    /// Get the price the user has set for the order, if any. Market orders, stop orders without
+    /// a limit and pegged orders don't have one.
+    pub fn limit_price(&self) -> Option<Price> {
+        match self {
+            Order::Limit(order) if order.peg.is_none() => Some(order.price),
+            Order::Stop(order) => order.limit_price,
+            _ => None,
+        }
+    }
+
+    /// Get the fee rates the order trades at.
+    pub fn fee_rates(&self) -> FeeRates {
+        match self {
+            Order::Limit(order) => order.fee_rates,
+            Order::Market(order) => order.fee_rates,
+            Order::Stop(order) => order.fee_rates,
+        }
+    }
+
+    /// Set the fee rates the order trades at.
+    pub fn set_fee_rates(&mut self, fee_rates: FeeRates) {
+        match self {
+            Order::Limit(order) => order.fee_rates = fee_rates,
+            Order::Market(order) => order.fee_rates = fee_rates,
+            Order::Stop(order) => order.fee_rates = fee_rates,
+        }
+    }
+
+    /// Get the peg of a pegged order.
+    pub fn peg(&self) -> Option<Peg> {
+        match self {
+            Order::Limit(order) => order.peg,
+            _ => None,
         }
     }
 
-    /// Check if this order is done, that is, if the quantitity is zero.
+    /// Check if this order is done, that is, if the quantitity is zero and there is nothing left
+    /// in the reserve.
     pub fn is_done(&self) -> bool {
-        self.quantity() == 0
+        match self {
+            Order::Limit(order) => order.quantity == 0 && order.reserve == 0,
+            _ => self.quantity() == 0,
+        }
     }
 
-    pub fn match_to(&mut self, other: &mut Self) -> Trade {
-        // This function will probably change significantly when new types of orders are introduced.
-        // For now we just stick to the simplest possible implementation.
+    /// Hide everything above the display quantity of an iceberg order in the reserve. This is
+    /// done when the order comes to rest in the book, since it trades its whole quantity when
+    /// it is incoming.
+    pub fn hide_reserve(&mut self) {
+        if let Order::Limit(order) = self {
+            if let Some(display_quantity) = order.display_quantity {
+                let hidden = order.quantity.saturating_sub(display_quantity);
+                order.quantity -= hidden;
+                order.reserve += hidden;
+            }
+        }
+    }
+
+    /// Move the whole reserve of an iceberg order back to its displayed quantity, so that it can
+    /// trade as an incoming order again.
+    pub fn reveal_reserve(&mut self) {
+        if let Order::Limit(order) = self {
+            order.quantity += order.reserve;
+            order.reserve = 0;
+        }
+    }
+
+    /// Replenish the displayed quantity of an iceberg order from its reserve once the displayed
+    /// quantity has been filled. Returns true if the order has been replenished.
+    pub fn replenish(&mut self) -> bool {
         match self {
-            Order::Limit(order) => match other {
-                Order::Limit(other_order) => order.match_to(other_order),
-            },
+            Order::Limit(order) if order.quantity == 0 && order.reserve > 0 => {
+                let display_quantity = order.display_quantity.unwrap_or(order.reserve);
+                order.quantity = display_quantity.min(order.reserve);
+                order.reserve -= order.quantity;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Match this incoming order with the other order resting in the book. The incoming order is
+    /// the aggressor: it takes the liquidity and pays the taker fee, while the passive resting
+    /// one pays the maker fee. The trade is given its id and time by the market.
+    pub fn match_to(&mut self, other: &mut Self) -> Trade {
+        let taker_side = self.side();
+        let taker_fee = self.fee_rates().taker;
+        let maker_fee = other.fee_rates().maker;
+        let (bid, ask) = match (self.side(), other.side()) {
+            (Side::Bid, Side::Ask) => (self, other),
+            (Side::Ask, Side::Bid) => (other, self),
+            _ => panic!("Cannot trade with on the same side"),
+        };
+        debug_assert!(bid.symbol() == ask.symbol(), "Trade symbols don't match");
+        debug_assert!(bid.price() >= ask.price(), "Bid must be greater than ask");
+
+        // Trades are made at the ask price, unless the ask is a market order that doesn't have
+        // a price of its own.
+        let price = match ask {
+            Order::Market(_) => bid.price(),
+            _ => ask.price(),
+        };
+        let trade_quantity = bid.quantity().min(ask.quantity());
+        *bid.quantity_mut() -= trade_quantity;
+        *ask.quantity_mut() -= trade_quantity;
+        let value = price.0 * trade_quantity as f64;
+        let (fee_buy, fee_sell) = match taker_side {
+            Side::Bid => (taker_fee * value, maker_fee * value),
+            Side::Ask => (maker_fee * value, taker_fee * value),
+        };
+
+        Trade {
+            trade_id: 0,
+            timestamp: 0,
+            order_id_buy: bid.order_id(),
+            order_id_sell: ask.order_id(),
+            aggressor: Some(taker_side),
+            user_id_buy: bid.user_id(),
+            user_order_id_buy: bid.user_order_id(),
+            user_id_sell: ask.user_id(),
+            user_order_id_sell: ask.user_order_id(),
+            symbol: bid.symbol(),
+            price,
+            quantity: trade_quantity,
+            fee_buy,
+            fee_sell,
         }
     }
 }
 
+/// The worst possible price for the given side, which any opposite order would match.
+fn market_price(side: Side) -> Price {
+    match side {
+        Side::Bid => f64::INFINITY.into(),
+        Side::Ask => 0.0.into(),
+    }
+}
+
+/// What to do with a post-only order that would match immediately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostOnly {
+    /// Reject the order.
+    Reject,
+    /// Reprice the order one tick away from the best opposite price.
+    Reprice,
+}
+
+/// Reference price a pegged order follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PegReference {
+    /// The best price on the same side of the book.
+    Primary,
+    /// The best price on the opposite side of the book.
+    Market,
+    /// The midpoint between the best bid and the best ask.
+    Midpoint,
+}
+
+/// Peg describes how the price of a pegged order follows the book: the reference price plus an
+/// offset, but never worse for the counterparty than the optional limit price.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset: Price,
+    pub limit: Option<Price>,
+}
+
 /// Convenience struct for building orders.
 pub struct OrderBuilder {
     user_id: u64,
     user_order_id: u64,
+    display_quantity: Option<u64>,
+    post_only: Option<PostOnly>,
+    min_quantity: Option<u64>,
+    all_or_none: bool,
+    hidden: bool,
+    fee_rates: FeeRates,
+    order_id: u64,
 }
 
 impl OrderBuilder {
@@ -102,17 +347,169 @@ impl OrderBuilder {
         Self {
             user_id,
             user_order_id,
+            display_quantity: None,
+            post_only: None,
+            min_quantity: None,
+            all_or_none: false,
+            hidden: false,
+            fee_rates: FeeRates::default(),
+            order_id: 0,
         }
     }
 
+    /// Let the order trade at the given fee rates.
+    pub fn fee_rates(mut self, fee_rates: FeeRates) -> Self {
+        self.fee_rates = fee_rates;
+        self
+    }
+
+    /// Give the order the id assigned by the exchange.
+    pub fn order_id(mut self, order_id: u64) -> Self {
+        self.order_id = order_id;
+        self
+    }
+
+    /// Let the order trade on arrival only if at least the given quantity can be executed
+    /// immediately.
+    pub fn min_quantity(mut self, min_quantity: u64) -> Self {
+        self.min_quantity = Some(min_quantity);
+        self
+    }
+
+    /// Make the limit order an all-or-none order, which is only ever filled completely.
+    pub fn all_or_none(mut self) -> Self {
+        self.all_or_none = true;
+        self
+    }
+
+    /// Make the limit order a hidden order, which is not displayed in the book at all.
+    pub fn hidden(mut self) -> Self {
+        self.hidden = true;
+        self
+    }
+
+    /// Make the limit order a post-only order, which never takes liquidity from the book.
+    pub fn post_only(mut self, post_only: PostOnly) -> Self {
+        self.post_only = Some(post_only);
+        self
+    }
+
+    /// Make the limit order an iceberg order that displays at most the given quantity in the
+    /// book at a time.
+    pub fn display_quantity(mut self, display_quantity: u64) -> Self {
+        self.display_quantity = Some(display_quantity);
+        self
+    }
+
     pub fn limit_order(self, side: Side, symbol: Symbol, price: f64, quantity: u64) -> Order {
-        Order::Limit(LimitOrder {
+        Order::Limit(self.build_limit_order(side, symbol, price.into(), quantity, None))
+    }
+
+    /// Build a pegged limit order. Its price is set by the book once it is added.
+    pub fn pegged_order(self, side: Side, symbol: Symbol, peg: Peg, quantity: u64) -> Order {
+        let price = peg.limit.unwrap_or_else(|| market_price(side));
+        Order::Limit(self.build_limit_order(side, symbol, price, quantity, Some(peg)))
+    }
+
+    fn build_limit_order(
+        self,
+        side: Side,
+        symbol: Symbol,
+        price: Price,
+        quantity: u64,
+        peg: Option<Peg>,
+    ) -> LimitOrder {
+        LimitOrder {
+            user_id: self.user_id,
+            user_order_id: self.user_order_id,
+            side,
+            symbol,
+            price,
+            quantity,
+            reserve: 0,
+            display_quantity: self.display_quantity,
+            post_only: self.post_only,
+            peg,
+            min_quantity: self.min_quantity,
+            all_or_none: self.all_or_none,
+            hidden: self.hidden,
+            fee_rates: self.fee_rates,
+            order_id: self.order_id,
+        }
+    }
+
+    pub fn market_order(self, side: Side, symbol: Symbol, quantity: u64) -> Order {
+        Order::Market(MarketOrder {
+            user_id: self.user_id,
+            user_order_id: self.user_order_id,
+            side,
+            symbol,
+            quantity,
+            min_quantity: self.min_quantity,
+            fee_rates: self.fee_rates,
+            order_id: self.order_id,
+        })
+    }
+
+    /// Build a stop order that turns into a market order once triggered.
+    pub fn stop_order(self, side: Side, symbol: Symbol, stop_price: f64, quantity: u64) -> Order {
+        Order::Stop(StopOrder {
+            user_id: self.user_id,
+            user_order_id: self.user_order_id,
+            side,
+            symbol,
+            stop_price: stop_price.into(),
+            limit_price: None,
+            quantity,
+            trail: None,
+            fee_rates: self.fee_rates,
+            order_id: self.order_id,
+        })
+    }
+
+    /// Build a trailing stop order that turns into a market order once triggered. Its stop price
+    /// is set by the book from the last trade price.
+    pub fn trailing_stop_order(
+        self,
+        side: Side,
+        symbol: Symbol,
+        trail: Trail,
+        quantity: u64,
+    ) -> Order {
+        Order::Stop(StopOrder {
             user_id: self.user_id,
             user_order_id: self.user_order_id,
             side,
             symbol,
-            price: price.into(),
+            stop_price: market_price(side),
+            limit_price: None,
             quantity,
+            trail: Some(trail),
+            fee_rates: self.fee_rates,
+            order_id: self.order_id,
+        })
+    }
+
+    /// Build a stop order that turns into a limit order once triggered.
+    pub fn stop_limit_order(
+        self,
+        side: Side,
+        symbol: Symbol,
+        stop_price: f64,
+        price: f64,
+        quantity: u64,
+    ) -> Order {
+        Order::Stop(StopOrder {
+            user_id: self.user_id,
+            user_order_id: self.user_order_id,
+            side,
+            symbol,
+            stop_price: stop_price.into(),
+            limit_price: Some(price.into()),
+            quantity,
+            trail: None,
+            fee_rates: self.fee_rates,
+            order_id: self.order_id,
         })
     }
 }
@@ -121,38 +518,121 @@ impl OrderBuilder {
 /// a security at a specific price or better.  If the side is 'bid', the price represents the maximum
 /// price that a buyer is willing to pay for a share of stock or other security. If the side is 'ask',
 /// the price represents the minimum price that a seller is willing to take for that same security.
-#[derive(Debug)]
+///
+/// An iceberg order has a display quantity. Once it rests in the book, only up to the display
+/// quantity is visible and matched, while the rest is kept in the reserve. Whenever the displayed
+/// quantity is filled, it is replenished from the reserve and the order loses its time priority.
+///
+/// A post-only order only adds liquidity to the book: if it would match immediately, it is
+/// either rejected or repriced so that it doesn't.
+///
+/// The price of a pegged order is not fixed, instead it follows a reference price of the book.
+///
+/// An order with a minimum quantity only trades on arrival if at least that much can be executed
+/// immediately, otherwise it rests in the book without trading. An all-or-none order is never
+/// filled partially: it doesn't trade on arrival unless it can be filled completely, and while
+/// resting, the matcher skips it for incoming orders that are too small.
+///
+/// A hidden order rests and matches like any other limit order, but it is left out of the top of
+/// the book and the market data, and it yields to the displayed orders at the same price.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LimitOrder {
     pub user_id: u64,
     pub user_order_id: u64,
     pub side: Side,
+    #[serde(with = "serde_symbol")]
     pub symbol: Symbol,
     pub price: Price,
     pub quantity: u64,
+    pub reserve: u64,
+    pub display_quantity: Option<u64>,
+    pub post_only: Option<PostOnly>,
+    pub peg: Option<Peg>,
+    pub min_quantity: Option<u64>,
+    pub all_or_none: bool,
+    pub hidden: bool,
+    pub fee_rates: FeeRates,
+    pub order_id: u64,
 }
 
-impl LimitOrder {
-    pub fn match_to(&mut self, other: &mut Self) -> Trade {
-        let (bid, ask) = match (self.side, other.side) {
-            (Side::Bid, Side::Ask) => (self, other),
-            (Side::Ask, Side::Bid) => (other, self),
-            _ => panic!("Cannot trade with on the same side"),
-        };
-        debug_assert!(bid.symbol == ask.symbol, "Trade symbols don't match");
-        debug_assert!(bid.price >= ask.price, "Bid must be greater than ask");
+/// MarketOrder represents a market order. A market order is executed immediately at the best
+/// available prices. It never rests in the book: whatever can't be filled immediately is
+/// cancelled, including the whole order if less than its minimum quantity can be filled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketOrder {
+    pub user_id: u64,
+    pub user_order_id: u64,
+    pub side: Side,
+    #[serde(with = "serde_symbol")]
+    pub symbol: Symbol,
+    pub quantity: u64,
+    pub min_quantity: Option<u64>,
+    pub fee_rates: FeeRates,
+    pub order_id: u64,
+}
+
+/// StopOrder represents a stop or a stop-limit order. It is held off the book until the last
+/// trade price reaches the stop price: at or above it for bids, at or below it for asks. Then it
+/// is turned into a market order, or into a limit order if it has a limit price.
+///
+/// The stop price of a trailing stop order follows the last trade price at a fixed distance, but
+/// only in the favourable direction: up for asks, which protect against the price falling, and
+/// down for bids.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopOrder {
+    pub user_id: u64,
+    pub user_order_id: u64,
+    pub side: Side,
+    #[serde(with = "serde_symbol")]
+    pub symbol: Symbol,
+    pub stop_price: Price,
+    pub limit_price: Option<Price>,
+    pub quantity: u64,
+    pub trail: Option<Trail>,
+    pub fee_rates: FeeRates,
+    pub order_id: u64,
+}
 
-        let trade_quantity = bid.quantity.min(ask.quantity);
-        bid.quantity -= trade_quantity;
-        ask.quantity -= trade_quantity;
+/// Distance of the stop price of a trailing stop order from the last trade price.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Trail {
+    /// A fixed price difference.
+    Amount(Price),
+    /// A percentage of the last trade price.
+    Percent(f64),
+}
 
-        Trade {
-            user_id_buy: bid.user_id,
-            user_order_id_buy: bid.user_order_id,
-            user_id_sell: ask.user_id,
-            user_order_id_sell: ask.user_order_id,
-            symbol: bid.symbol,
-            price: ask.price,
-            quantity: trade_quantity,
+impl StopOrder {
+    /// Check if the order is triggered by the given last trade price.
+    pub fn is_triggered(&self, last_price: Price) -> bool {
+        match self.side {
+            Side::Bid => last_price >= self.stop_price,
+            Side::Ask => last_price <= self.stop_price,
+        }
+    }
+
+    /// Move the stop price of a trailing stop order after the last trade price, if it moves in the
+    /// favourable direction.
+    pub fn trail(&mut self, last_price: Price) {
+        let distance = match self.trail {
+            Some(Trail::Amount(amount)) => amount,
+            Some(Trail::Percent(percent)) => last_price * percent / 100.0,
+            None => return,
+        };
+        self.stop_price = match self.side {
+            Side::Bid => self.stop_price.min(last_price + distance),
+            Side::Ask => self.stop_price.max(last_price - distance),
+        };
+    }
+
+    /// Turn the triggered stop order into the order it stands for.
+    pub fn trigger(self) -> Order {
+        let builder = OrderBuilder::new(self.user_id, self.user_order_id)
+            .fee_rates(self.fee_rates)
+            .order_id(self.order_id);
+        match self.limit_price {
+            Some(price) => builder.limit_order(self.side, self.symbol, price.0, self.quantity),
+            None => builder.market_order(self.side, self.symbol, self.quantity),
         }
     }
 }
@@ -238,4 +718,98 @@ mod tests {
         let mut ask_order = Order::with_ids(2, 102).limit_order(Side::Bid, aapl, 1.0, 10);
         bid_order.match_to(&mut ask_order);
     }
+
+    #[test]
+    fn test_market_ask_trades_at_bid_price() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let mut bid_order = Order::with_ids(1, 101).limit_order(Side::Bid, aapl, 3.0, 10);
+        let mut ask_order = Order::with_ids(2, 102).market_order(Side::Ask, aapl, 4);
+        let trade = ask_order.match_to(&mut bid_order);
+        assert_eq!(trade.price, 3.0);
+        assert_eq!(trade.quantity, 4);
+        assert_eq!(bid_order.quantity(), 6);
+    }
+
+    #[test]
+    fn test_maker_and_taker_fees() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let rates = FeeRates {
+            maker: -0.001,
+            taker: 0.002,
+        };
+        let mut ask_order =
+            Order::with_ids(1, 101)
+                .fee_rates(rates)
+                .limit_order(Side::Ask, aapl, 10.0, 10);
+        let mut bid_order =
+            Order::with_ids(2, 102)
+                .fee_rates(rates)
+                .limit_order(Side::Bid, aapl, 11.0, 5);
+        let mut trade = bid_order.match_to(&mut ask_order);
+        assert_eq!(trade.fee_buy, 0.1);
+        assert_eq!(trade.fee_sell, -0.05);
+
+        trade.set_price(20.0.into());
+        assert_eq!(trade.fee_buy, 0.2);
+        assert_eq!(trade.fee_sell, -0.1);
+    }
+
+    #[test]
+    fn test_stop_trigger() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let stop = StopOrder {
+            user_id: 1,
+            user_order_id: 101,
+            side: Side::Ask,
+            symbol: aapl,
+            stop_price: 5.0.into(),
+            limit_price: Some(4.0.into()),
+            quantity: 10,
+            trail: None,
+            fee_rates: FeeRates::default(),
+            order_id: 0,
+        };
+        assert!(!stop.is_triggered(5.5.into()));
+        assert!(stop.is_triggered(5.0.into()));
+        assert!(stop.is_triggered(4.5.into()));
+        assert_eq!(
+            stop.trigger(),
+            Order::with_ids(1, 101).limit_order(Side::Ask, aapl, 4.0, 10)
+        );
+    }
+
+    #[test]
+    fn test_trailing_stop_ratchets() {
+        let mut si = StringInterner::default();
+        let aapl = si.get_or_intern_static("AAPL");
+        let trail = Trail::Amount(2.0.into());
+        let mut stop = match Order::with_ids(1, 101).trailing_stop_order(Side::Ask, aapl, trail, 10)
+        {
+            Order::Stop(stop) => stop,
+            _ => unreachable!(),
+        };
+        stop.trail(10.0.into());
+        assert_eq!(stop.stop_price, 8.0);
+        stop.trail(13.0.into());
+        assert_eq!(stop.stop_price, 11.0);
+        stop.trail(12.0.into());
+        assert_eq!(stop.stop_price, 11.0);
+        assert!(stop.is_triggered(11.0.into()));
+
+        let trail = Trail::Percent(10.0);
+        let mut stop = match Order::with_ids(1, 102).trailing_stop_order(Side::Bid, aapl, trail, 10)
+        {
+            Order::Stop(stop) => stop,
+            _ => unreachable!(),
+        };
+        stop.trail(20.0.into());
+        assert_eq!(stop.stop_price, 22.0);
+        stop.trail(30.0.into());
+        assert_eq!(stop.stop_price, 22.0);
+        stop.trail(10.0.into());
+        assert_eq!(stop.stop_price, 11.0);
+    }
 }
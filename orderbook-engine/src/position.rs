@@ -0,0 +1,149 @@
+//! This module contains the position keeper, which follows the trades of the users and keeps
+//! their positions and profit and loss in each security.
+
+use crate::order::Side;
+use crate::trade::Trade;
+use crate::{Price, Symbol};
+
+use std::collections::HashMap;
+
+/// Position of a user in a security. The average cost is the average price the open quantity was
+/// bought or sold at, and the realized profit and loss is what closing a part of the position at
+/// a different price has made or lost so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    /// Net quantity, positive if long and negative if short.
+    pub quantity: i64,
+    pub average_cost: f64,
+    pub realized_pnl: f64,
+}
+
+impl Position {
+    /// Update the position with a fill of the given side, price and quantity. A fill on the
+    /// side of the position increases it at a new average cost, while a fill on the other side
+    /// closes it, realizing the difference from the average cost, and may open a new position on
+    /// the other side at the price of the fill.
+    pub fn fill(&mut self, side: Side, price: Price, quantity: u64) {
+        let quantity = match side {
+            Side::Bid => quantity as i64,
+            Side::Ask => -(quantity as i64),
+        };
+        let price = price.0;
+        let total = self.quantity + quantity;
+        if self.quantity.signum() * quantity.signum() >= 0 {
+            let cost =
+                self.average_cost * self.quantity.abs() as f64 + price * quantity.abs() as f64;
+            self.average_cost = cost / total.abs() as f64;
+        } else {
+            let closed = quantity.abs().min(self.quantity.abs());
+            let direction = self.quantity.signum() as f64;
+            self.realized_pnl += (price - self.average_cost) * closed as f64 * direction;
+            if total == 0 {
+                self.average_cost = 0.0;
+            } else if total.signum() == quantity.signum() {
+                self.average_cost = price;
+            }
+        }
+        self.quantity = total;
+    }
+
+    /// Get the profit and loss of the open quantity if it was closed at the given price.
+    pub fn unrealized_pnl(&self, mark_price: Price) -> f64 {
+        (mark_price.0 - self.average_cost) * self.quantity as f64
+    }
+}
+
+/// Position of a user in a security together with its unrealized profit and loss, marked to the
+/// midpoint of the book or, if the book has no quotes on either side, to the last trade price.
+/// Without either, the position isn't marked and the unrealized profit and loss is zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionReport {
+    pub symbol: Symbol,
+    pub position: Position,
+    pub mark_price: Option<Price>,
+    pub unrealized_pnl: f64,
+}
+
+/// Positions keeps the positions of all the users in all the securities they have traded.
+#[derive(Debug, Default)]
+pub(crate) struct Positions {
+    positions: HashMap<(u64, Symbol), Position>,
+}
+
+impl Positions {
+    /// Update the positions of both users of the trade.
+    pub fn record(&mut self, trade: &Trade) {
+        self.positions
+            .entry((trade.user_id_buy, trade.symbol))
+            .or_default()
+            .fill(Side::Bid, trade.price, trade.quantity);
+        self.positions
+            .entry((trade.user_id_sell, trade.symbol))
+            .or_default()
+            .fill(Side::Ask, trade.price, trade.quantity);
+    }
+
+    /// Get the position of the user in the security.
+    pub fn get(&self, user_id: u64, symbol: Symbol) -> Position {
+        self.positions
+            .get(&(user_id, symbol))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((u64, Symbol), Position)> + '_ {
+        self.positions
+            .iter()
+            .map(|(&key, &position)| (key, position))
+    }
+
+    pub fn restore(&mut self, positions: impl IntoIterator<Item = ((u64, Symbol), Position)>) {
+        self.positions = positions.into_iter().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(position: &mut Position, side: Side, price: f64, quantity: u64) {
+        position.fill(side, price.into(), quantity);
+    }
+
+    #[test]
+    fn test_increasing_position_averages_cost() {
+        let mut position = Position::default();
+        fill(&mut position, Side::Bid, 10.0, 100);
+        fill(&mut position, Side::Bid, 13.0, 50);
+        assert_eq!(position.quantity, 150);
+        assert_eq!(position.average_cost, 11.0);
+        assert_eq!(position.realized_pnl, 0.0);
+        assert_eq!(position.unrealized_pnl(12.0.into()), 150.0);
+    }
+
+    #[test]
+    fn test_closing_position_realizes_pnl() {
+        let mut position = Position::default();
+        fill(&mut position, Side::Ask, 20.0, 100);
+        fill(&mut position, Side::Bid, 18.0, 40);
+        assert_eq!(position.quantity, -60);
+        assert_eq!(position.average_cost, 20.0);
+        assert_eq!(position.realized_pnl, 80.0);
+        assert_eq!(position.unrealized_pnl(21.0.into()), -60.0);
+
+        fill(&mut position, Side::Bid, 22.0, 60);
+        assert_eq!(position.quantity, 0);
+        assert_eq!(position.average_cost, 0.0);
+        assert_eq!(position.realized_pnl, -40.0);
+    }
+
+    #[test]
+    fn test_reversing_position_opens_at_fill_price() {
+        let mut position = Position::default();
+        fill(&mut position, Side::Bid, 10.0, 100);
+        fill(&mut position, Side::Ask, 12.0, 150);
+        assert_eq!(position.quantity, -50);
+        assert_eq!(position.average_cost, 12.0);
+        assert_eq!(position.realized_pnl, 200.0);
+    }
+}
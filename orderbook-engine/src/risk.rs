@@ -0,0 +1,101 @@
+//! This module contains the pre-trade risk checks the market runs before accepting an order.
+
+use crate::book::Book;
+use crate::event::RejectReason;
+use crate::order::{Order, Side};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+/// Limits of the pre-trade risk checks of a user. Limits that aren't set aren't checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskLimits {
+    /// The largest quantity of a single order.
+    pub max_quantity: Option<u64>,
+    /// The largest value of a single order, i.e. its price times its quantity. Pegged orders are
+    /// valued at their peg price, and orders without a price at the best opposite price.
+    pub max_notional: Option<f64>,
+    /// The largest distance of the order price from the best opposite price, or the best price
+    /// on the same side if there is no opposite one, as a fraction of that price. Pegged orders
+    /// are checked at their peg price.
+    pub max_price_deviation: Option<f64>,
+    /// The largest number of orders of the user on the market at the same time.
+    pub max_open_orders: Option<usize>,
+    /// The largest net position of the user in a single security, long or short, that the order
+    /// could lead to if it was filled completely.
+    pub max_position: Option<u64>,
+}
+
+/// Risk keeps the risk limits of the users, falling back to the default limits for users without
+/// limits of their own.
+#[derive(Debug, Default)]
+pub(crate) struct Risk {
+    default: RiskLimits,
+    users: HashMap<u64, RiskLimits>,
+}
+
+impl Risk {
+    pub fn set_default_limits(&mut self, limits: RiskLimits) {
+        self.default = limits;
+    }
+
+    pub fn set_limits(&mut self, user_id: u64, limits: RiskLimits) {
+        self.users.insert(user_id, limits);
+    }
+
+    fn limits(&self, user_id: u64) -> &RiskLimits {
+        self.users.get(&user_id).unwrap_or(&self.default)
+    }
+
+    /// Check the order against the limits of its user, given the book of its security, the
+    /// number of orders the user already has on the market and the net position of the user in
+    /// the security.
+    pub fn check(
+        &self,
+        order: &Order,
+        book: &Book,
+        open_orders: usize,
+        position: i64,
+    ) -> Result<(), RejectReason> {
+        let limits = self.limits(order.user_id());
+        let quantity = order.quantity();
+        if limits.max_quantity.is_some_and(|max| quantity > max) {
+            return Err(RejectReason::QuantityLimit);
+        }
+        if limits.max_open_orders.is_some_and(|max| open_orders >= max) {
+            return Err(RejectReason::OpenOrderLimit);
+        }
+        let opposite = book.top_of_book(order.side().opposite());
+        let price = order.limit_price().or_else(|| {
+            order
+                .peg()
+                .and_then(|peg| book.peg_price(order.side(), peg))
+        });
+        let value = price.or(opposite.map(|(price, _)| price));
+        if let (Some(max), Some(value)) = (limits.max_notional, value) {
+            if value.0 * quantity as f64 > max {
+                return Err(RejectReason::NotionalLimit);
+            }
+        }
+        let reference = opposite.or_else(|| book.top_of_book(order.side()));
+        if let (Some(max), Some(price), Some((reference, _))) =
+            (limits.max_price_deviation, price, reference)
+        {
+            if (price.0 - reference.0).abs() > reference.0 * max {
+                return Err(RejectReason::PriceDeviation);
+            }
+        }
+        if let Some(max) = limits.max_position {
+            let position = match order.side() {
+                Side::Bid => position + quantity as i64,
+                Side::Ask => position - quantity as i64,
+            };
+            if position.unsigned_abs() > max {
+                return Err(RejectReason::PositionLimit);
+            }
+        }
+        Ok(())
+    }
+}
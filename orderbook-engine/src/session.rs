@@ -0,0 +1,102 @@
+//! This module contains the definition of the trading phases of an instrument and the rules of
+//! what is allowed in each of them.
+
+use crate::event::RejectReason;
+use crate::order::Order;
+
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+/// Phase is the state of the trading session of an instrument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Phase {
+    /// Orders can be entered and cancelled, but they don't match.
+    PreOpen,
+    /// Call auction. Orders accumulate without matching and the indicative price is published.
+    Auction,
+    /// Continuous matching.
+    #[default]
+    Continuous,
+    /// Trading is suspended. Orders can only be cancelled.
+    Halted,
+    /// The session is over. Nothing can be entered or cancelled.
+    Closed,
+}
+
+impl Phase {
+    /// Check if the phase may be followed by the given one. A session goes from pre-open through
+    /// an opening auction to continuous trading, may be halted and reopened, and ends with an
+    /// optional closing auction.
+    pub fn can_become(self, next: Phase) -> bool {
+        use Phase::*;
+        matches!(
+            (self, next),
+            (PreOpen, Auction)
+                | (PreOpen, Continuous)
+                | (PreOpen, Closed)
+                | (Auction, Continuous)
+                | (Auction, Halted)
+                | (Auction, Closed)
+                | (Continuous, Auction)
+                | (Continuous, Halted)
+                | (Continuous, Closed)
+                | (Halted, Auction)
+                | (Halted, Continuous)
+                | (Halted, Closed)
+                | (Closed, PreOpen)
+        )
+    }
+
+    /// Check if incoming orders are matched in this phase.
+    pub fn is_matching(self) -> bool {
+        self == Phase::Continuous
+    }
+
+    /// Check if the order may be entered in this phase. Market orders can't wait for matching,
+    /// so they are only allowed in continuous trading.
+    pub fn check_order(self, order: &Order) -> Result<(), RejectReason> {
+        match (self, order) {
+            (Phase::Continuous, _) => Ok(()),
+            (Phase::PreOpen, Order::Market(_)) | (Phase::Auction, Order::Market(_)) => {
+                Err(RejectReason::NotAllowed(self))
+            }
+            (Phase::PreOpen, _) | (Phase::Auction, _) => Ok(()),
+            (Phase::Halted, _) | (Phase::Closed, _) => Err(RejectReason::NotAllowed(self)),
+        }
+    }
+
+    /// Check if orders may be cancelled in this phase.
+    pub fn check_cancel(self) -> Result<(), RejectReason> {
+        match self {
+            Phase::Closed => Err(RejectReason::NotAllowed(self)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// CircuitBreaker halts trading in an instrument whose price moves too far too fast. An incoming
+/// order may only trade within the band around the reference price, i.e. the last trade or
+/// auction price. If it would trade outside of it, matching stops and the instrument is halted
+/// for a while, after which it reopens with a short call auction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitBreaker {
+    /// Width of the band on either side of the reference price as a fraction of the price.
+    pub band: f64,
+    /// How long trading is halted.
+    pub halt: Duration,
+    /// How long the reopening auction lasts.
+    pub reopening: Duration,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Phase::PreOpen => write!(f, "pre-open"),
+            Phase::Auction => write!(f, "auction"),
+            Phase::Continuous => write!(f, "continuous"),
+            Phase::Halted => write!(f, "halted"),
+            Phase::Closed => write!(f, "closed"),
+        }
+    }
+}
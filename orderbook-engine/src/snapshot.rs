@@ -0,0 +1,154 @@
+//! This module contains the definition of a market snapshot, a complete serialized state of the
+//! market that can be stored in a file and loaded back later.
+
+use crate::book::Book;
+use crate::fee::FeeTotal;
+use crate::group::Link;
+use crate::ledger::Account;
+use crate::market::Index;
+use crate::Symbol;
+
+use serde::{Deserialize, Serialize};
+use string_interner::StringInterner;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Snapshot of the market state. Besides the books, indices, order groups, positions, balances
+/// and fee totals, it records the position of the last command applied to the market, so that a
+/// restored market can be brought up to date by replaying the commands that came after it. The
+/// ledger entries themselves aren't part of the snapshot, only the balances they have led to and
+/// the sequence number of the last one, so that the numbering of the entries carries on. Every
+/// field is required, so a snapshot missing any of them fails to load.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub position: u64,
+    pub(crate) symbols: StringInterner,
+    pub(crate) books: Vec<BookSnapshot>,
+    pub(crate) indices: Vec<Index>,
+    pub(crate) groups: Vec<((u64, u64), Link)>,
+    pub(crate) positions: Vec<PositionSnapshot>,
+    pub(crate) balances: Vec<BalanceSnapshot>,
+    pub(crate) reservations: Vec<ReservationSnapshot>,
+    pub(crate) fee_totals: Vec<FeeTotalSnapshot>,
+    pub(crate) halted: bool,
+    pub(crate) disabled_users: Vec<u64>,
+    pub(crate) sequence: u64,
+    pub(crate) last_order_id: u64,
+    pub(crate) last_trade_id: u64,
+    pub(crate) used_ids: Option<Vec<(u64, u64)>>,
+    pub(crate) ledger_sequence: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BookSnapshot {
+    #[serde(with = "serde_symbol")]
+    pub symbol: Symbol,
+    pub book: Book,
+}
+
+/// Position of a user in a security: the net quantity, its average cost and the realized profit
+/// and loss.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PositionSnapshot {
+    pub user_id: u64,
+    #[serde(with = "serde_symbol")]
+    pub symbol: Symbol,
+    pub position: i64,
+    pub average_cost: f64,
+    pub realized_pnl: f64,
+}
+
+/// Balance of a ledger account in an asset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BalanceSnapshot {
+    pub account: Account,
+    #[serde(with = "serde_symbol")]
+    pub asset: Symbol,
+    pub balance: f64,
+}
+
+/// Funds reserved for an order that hasn't left the market yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ReservationSnapshot {
+    pub user_id: u64,
+    pub user_order_id: u64,
+    #[serde(with = "serde_symbol")]
+    pub asset: Symbol,
+    pub amount: f64,
+}
+
+/// Fee total of a user in a security since the billing period started.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FeeTotalSnapshot {
+    pub user_id: u64,
+    #[serde(with = "serde_symbol")]
+    pub symbol: Symbol,
+    pub total: FeeTotal,
+}
+
+impl Snapshot {
+    /// Write the snapshot to a file. The snapshot is written to a temporary file first and then
+    /// renamed, so an existing snapshot is never left half overwritten.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Load a snapshot from a file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+/// Symbols don't implement serde traits, so they are serialized as their numeric representation.
+/// This is only meaningful together with the interner that produced them.
+pub(crate) mod serde_symbol {
+    use crate::Symbol;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use string_interner::Symbol as _;
+
+    pub fn serialize<S: Serializer>(symbol: &Symbol, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(symbol.to_usize() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Symbol, D::Error> {
+        let value = u64::deserialize(deserializer)?;
+        Symbol::try_from_usize(value as usize).ok_or_else(|| D::Error::custom("invalid symbol"))
+    }
+}
+
+/// Maps with keys that are not strings can't be represented in JSON directly, so they are
+/// serialized as sequences of key and value pairs.
+pub(crate) mod serde_entries {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S, K, V>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        K: Serialize,
+        V: Serialize,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, D, K, V>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
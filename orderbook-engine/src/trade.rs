@@ -1,10 +1,20 @@
 //! This module contains the definition of a market trade.
 
+use crate::order::Side;
 use crate::{Price, Symbol};
 
-/// Trade represents a successful trade transaction on the market.
-#[derive(Debug)]
+/// Trade represents a successful trade transaction on the market. Trades are numbered by the
+/// market and stamped with the time in nanoseconds since the Unix epoch. Besides the user ids,
+/// the orders are referred to by the ids the exchange has assigned to them. The aggressor is the
+/// side of the incoming order that took the liquidity, which trades in an auction don't have. The
+/// fees of the buyer and the seller are amounts in the trade currency, negative for rebates.
+#[derive(Debug, Clone)]
 pub struct Trade {
+    pub trade_id: u64,
+    pub timestamp: u64,
+    pub order_id_buy: u64,
+    pub order_id_sell: u64,
+    pub aggressor: Option<Side>,
     pub user_id_buy: u64,
     pub user_order_id_buy: u64,
     pub user_id_sell: u64,
@@ -12,4 +22,40 @@ pub struct Trade {
     pub symbol: Symbol,
     pub price: Price,
     pub quantity: u64,
+    pub fee_buy: f64,
+    pub fee_sell: f64,
+}
+
+impl Trade {
+    /// Move the trade to another price, e.g. the equilibrium price of an auction. The fees are
+    /// proportional to the trade value, so they move along.
+    pub fn set_price(&mut self, price: Price) {
+        if self.price.0 != 0.0 {
+            let ratio = price.0 / self.price.0;
+            self.fee_buy *= ratio;
+            self.fee_sell *= ratio;
+        }
+        self.price = price;
+    }
+
+    /// Get the id of the order that took the liquidity, if any.
+    pub fn aggressor_order_id(&self) -> Option<u64> {
+        self.aggressor.map(|side| match side {
+            Side::Bid => self.order_id_buy,
+            Side::Ask => self.order_id_sell,
+        })
+    }
+
+    /// Get the id of the order that provided the liquidity, if the trade has an aggressor.
+    pub fn passive_order_id(&self) -> Option<u64> {
+        self.aggressor.map(|side| match side {
+            Side::Bid => self.order_id_sell,
+            Side::Ask => self.order_id_buy,
+        })
+    }
+
+    /// Check if any of the users pays a fee or gets a rebate for the trade.
+    pub fn has_fees(&self) -> bool {
+        self.fee_buy != 0.0 || self.fee_sell != 0.0
+    }
 }
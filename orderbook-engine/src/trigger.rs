@@ -0,0 +1,112 @@
+//! This module contains the definition of a trigger book, where stop orders wait off the order
+//! book until they are triggered by the last trade price.
+
+use crate::order::{Order, Side, StopOrder};
+use crate::snapshot::serde_entries;
+use crate::Price;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+/// Stop orders keyed by their stop price and arrival sequence number.
+type StopMap = BTreeMap<(Price, u64), StopOrder>;
+
+/// TriggerBook holds stop orders of one order book ordered by their stop prices. Bid stops are
+/// triggered when the last trade price rises to their stop price, so the lowest stop price is
+/// triggered first; ask stops are triggered when the price falls, so the highest is triggered
+/// first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TriggerBook {
+    #[serde(with = "serde_entries")]
+    bids: StopMap,
+    #[serde(with = "serde_entries")]
+    asks: StopMap,
+    next_sequence: u64,
+}
+
+impl TriggerBook {
+    /// Create an empty trigger book.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a stop order to the trigger book.
+    pub fn add(&mut self, order: StopOrder) {
+        let key = (order.stop_price, self.next_sequence);
+        self.next_sequence += 1;
+        match order.side {
+            Side::Bid => self.bids.insert(key, order),
+            Side::Ask => self.asks.insert(key, order),
+        };
+    }
+
+    /// Find a stop order given by order ids.
+    pub fn get(&self, user_id: u64, user_order_id: u64) -> Option<&StopOrder> {
+        self.bids
+            .values()
+            .chain(self.asks.values())
+            .find(|order| order.user_id == user_id && order.user_order_id == user_order_id)
+    }
+
+    /// Remove a stop order given by order ids.
+    pub fn remove(&mut self, user_id: u64, user_order_id: u64) -> Option<StopOrder> {
+        for stops in [&mut self.bids, &mut self.asks] {
+            let key = stops
+                .iter()
+                .find(|(_, order)| order.user_id == user_id && order.user_order_id == user_order_id)
+                .map(|(&key, _)| key);
+            if let Some(key) = key {
+                return stops.remove(&key);
+            }
+        }
+        None
+    }
+
+    /// Take all the stop orders triggered by the given last trade price. The triggered orders
+    /// are returned in the order they arrived to the market, regardless of their side and stop
+    /// price, so that releasing them is deterministic.
+    pub fn take_triggered(&mut self, last_price: Price) -> Vec<Order> {
+        let mut triggered = Vec::new();
+        while let Some((&key, order)) = self.bids.iter().next() {
+            if !order.is_triggered(last_price) {
+                break;
+            }
+            triggered.push((key.1, self.bids.remove(&key).unwrap()));
+        }
+        while let Some((&key, order)) = self.asks.iter().next_back() {
+            if !order.is_triggered(last_price) {
+                break;
+            }
+            triggered.push((key.1, self.asks.remove(&key).unwrap()));
+        }
+        triggered.sort_by_key(|(sequence, _)| *sequence);
+        triggered
+            .into_iter()
+            .map(|(_, order)| order.trigger())
+            .collect()
+    }
+
+    /// Move the stop prices of the trailing stop orders after the last trade price. They keep
+    /// their arrival sequence numbers.
+    pub fn trail(&mut self, last_price: Price) {
+        for stops in [&mut self.bids, &mut self.asks] {
+            let keys = stops
+                .iter()
+                .filter(|(_, order)| order.trail.is_some())
+                .map(|(&key, _)| key)
+                .collect::<Vec<_>>();
+            for key in keys {
+                let mut order = stops.remove(&key).unwrap();
+                order.trail(last_price);
+                stops.insert((order.stop_price, key.1), order);
+            }
+        }
+    }
+
+    /// Remove all stop orders.
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+}
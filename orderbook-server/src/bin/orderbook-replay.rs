@@ -0,0 +1,56 @@
+//! Replay commands from a CSV file or a journal directory through an in-process market, write
+//! the output in the published format and optionally compare it with the expected output.
+//!
+//! Usage: `orderbook-replay <input> [--expected <file>] [--output <file>] [--no-trading]
+//! [--sequenced]`
+
+use orderbook_server::replay::{self, Replay};
+
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+fn main() -> Result<()> {
+    let mut input = None;
+    let mut expected = None;
+    let mut output = None;
+    let mut trading = true;
+    let mut sequenced = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--expected" => expected = Some(PathBuf::from(args.next().context("Missing file")?)),
+            "--output" => output = Some(PathBuf::from(args.next().context("Missing file")?)),
+            "--no-trading" => trading = false,
+            "--sequenced" => sequenced = true,
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(anyhow!("Unrecognized argument {}", arg)),
+        }
+    }
+    let input = input.context("Missing input file")?;
+
+    let mut replay = Replay::new(trading);
+    replay.set_sequenced(sequenced);
+    let lines = replay::load(&input)?
+        .into_iter()
+        .flat_map(|(time, command)| replay.apply_at(time, command))
+        .collect::<Vec<_>>();
+    let mut text = lines.join("\n");
+    text.push('\n');
+    match output {
+        Some(path) => fs::write(path, text)?,
+        None => io::stdout().write_all(text.as_bytes())?,
+    }
+
+    if let Some(path) = expected {
+        let expected = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read the expected output {:?}", path))?;
+        let expected = replay::normalize(expected.lines());
+        if let Some(diff) = replay::diff(&expected, &replay::normalize(&lines)) {
+            eprint!("{}", diff);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
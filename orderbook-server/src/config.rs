@@ -0,0 +1,200 @@
+//! Command line configuration of the server.
+
+use crate::journal::{Fsync, JournalConfig};
+
+use orderbook_engine::prelude::{CircuitBreaker, FeeRates, RiskLimits};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default size of a journal segment before a new one is started.
+const DEFAULT_ROTATE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Default duration of a volatility halt in seconds.
+const DEFAULT_HALT_SECS: u64 = 300;
+
+/// Default duration of the auction reopening a halted security in seconds.
+const DEFAULT_REOPENING_SECS: u64 = 30;
+
+#[derive(Debug)]
+pub struct Config {
+    pub trading: bool,
+    pub journal: Option<JournalConfig>,
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: Option<u64>,
+    pub breaker: Option<CircuitBreaker>,
+    pub risk: RiskConfig,
+    pub funding: Option<String>,
+    pub fees: FeeConfig,
+    pub billing: Option<PathBuf>,
+    pub ledger: Option<PathBuf>,
+    pub admin_token: Option<String>,
+    pub sequenced: bool,
+    pub reject_reused_ids: bool,
+}
+
+/// Pre-trade risk limits: the default ones and those of the users with limits of their own.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RiskConfig {
+    pub default: RiskLimits,
+    pub users: HashMap<u64, RiskLimits>,
+}
+
+impl RiskConfig {
+    /// Load the risk limits from a JSON file, e.g.
+    /// `{"default": {"max_quantity": 1000}, "users": {"7": {"max_open_orders": 10}}}`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the risk limits {:?}", path))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse the risk limits {:?}", path))
+    }
+}
+
+/// Fee schedule: the default rates, the tiers of the users and the rates of the tiers, either in a
+/// single security or in all of them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FeeConfig {
+    pub default: FeeRates,
+    pub tiers: HashMap<u64, u32>,
+    pub rates: Vec<FeeRule>,
+}
+
+/// Fee rates of a tier, in the given security or in all the securities if there is none.
+#[derive(Debug, Deserialize)]
+pub struct FeeRule {
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub tier: u32,
+    #[serde(flatten)]
+    pub rates: FeeRates,
+}
+
+impl FeeConfig {
+    /// Load the fee schedule from a JSON file, e.g. `{"default": {"maker": 0.001, "taker":
+    /// 0.002}, "tiers": {"7": 1}, "rates": [{"tier": 1, "maker": -0.0005, "taker": 0.001}]}`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the fee schedule {:?}", path))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse the fee schedule {:?}", path))
+    }
+}
+
+impl Config {
+    /// Parse the configuration from command line arguments, excluding the program name.
+    ///
+    /// Supported options:
+    ///  - `--no-trading`: reject orders that would cross the book instead of matching them.
+    ///  - `--reject-reused-ids`: reject orders reusing the ids of filled or cancelled orders, and
+    ///    not only those of the orders on the market.
+    ///  - `--journal <dir>`: write accepted commands to a journal in the given directory and
+    ///    replay it on startup.
+    ///  - `--fsync <always|batch[:N]|off>`: when to sync the journal to disk.
+    ///  - `--rotate-bytes <N>`: start a new journal segment once the current one reaches N bytes.
+    ///  - `--snapshot <file>`: restore the market from the snapshot on startup and save it on
+    ///    shutdown.
+    ///  - `--snapshot-interval <N>`: also save the snapshot after every N commands.
+    ///  - `--price-band <percent>`: halt a security whose incoming order would trade further than
+    ///    the given percentage away from the last trade price.
+    ///  - `--halt-secs <N>`: how long such a volatility halt lasts.
+    ///  - `--reopening-secs <N>`: how long the auction reopening a halted security lasts.
+    ///  - `--risk-limits <file>`: check orders against the pre-trade risk limits in the file.
+    ///  - `--funding <asset>`: require orders to be backed by the balances of their users, with
+    ///    the given asset as cash.
+    ///  - `--fees <file>`: charge the maker and taker fees of the fee schedule in the file.
+    ///  - `--billing <file>`: export the fee totals of the users to the file on shutdown.
+    ///  - `--ledger <file>`: append the ledger entries to the file as they are recorded.
+    ///  - `--admin-token-file <file>`: accept admin commands authenticated by the token in the
+    ///    file. Without it, all admin commands are refused.
+    ///  - `--sequenced`: prefix every output line with the sequence number and the timestamp of
+    ///    its event.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut trading = true;
+        let mut journal_dir = None;
+        let mut fsync = Fsync::default();
+        let mut rotate_bytes = DEFAULT_ROTATE_BYTES;
+        let mut snapshot = None;
+        let mut snapshot_interval = None;
+        let mut price_band = None;
+        let mut halt_secs = DEFAULT_HALT_SECS;
+        let mut reopening_secs = DEFAULT_REOPENING_SECS;
+        let mut risk = RiskConfig::default();
+        let mut funding = None;
+        let mut fees = FeeConfig::default();
+        let mut billing = None;
+        let mut ledger = None;
+        let mut admin_token = None;
+        let mut sequenced = false;
+        let mut reject_reused_ids = false;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--no-trading" => trading = false,
+                "--sequenced" => sequenced = true,
+                "--reject-reused-ids" => reject_reused_ids = true,
+                "--journal" => journal_dir = Some(PathBuf::from(value()?)),
+                "--fsync" => fsync = value()?.parse()?,
+                "--rotate-bytes" => rotate_bytes = value()?.parse()?,
+                "--snapshot" => snapshot = Some(PathBuf::from(value()?)),
+                "--snapshot-interval" => snapshot_interval = Some(value()?.parse()?),
+                "--price-band" => price_band = Some(value()?.parse::<f64>()?),
+                "--halt-secs" => halt_secs = value()?.parse()?,
+                "--reopening-secs" => reopening_secs = value()?.parse()?,
+                "--risk-limits" => risk = RiskConfig::load(Path::new(&value()?))?,
+                "--funding" => funding = Some(value()?),
+                "--fees" => fees = FeeConfig::load(Path::new(&value()?))?,
+                "--billing" => billing = Some(PathBuf::from(value()?)),
+                "--ledger" => ledger = Some(PathBuf::from(value()?)),
+                "--admin-token-file" => admin_token = Some(load_token(Path::new(&value()?))?),
+                _ => return Err(anyhow!("Unrecognized argument {}", arg)),
+            }
+        }
+        Ok(Self {
+            trading,
+            journal: journal_dir.map(|dir| JournalConfig {
+                dir,
+                fsync,
+                rotate_bytes,
+            }),
+            snapshot,
+            snapshot_interval,
+            breaker: price_band.map(|percent| CircuitBreaker {
+                band: percent / 100.0,
+                halt: Duration::from_secs(halt_secs),
+                reopening: Duration::from_secs(reopening_secs),
+            }),
+            risk,
+            funding,
+            fees,
+            billing,
+            ledger,
+            admin_token,
+            sequenced,
+            reject_reused_ids,
+        })
+    }
+}
+
+/// Read the admin token from a file, ignoring the surrounding whitespace. The token is kept out
+/// of the command line, where other users of the machine could see it.
+fn load_token(path: &Path) -> Result<String> {
+    let token = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the admin token {:?}", path))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("The admin token {:?} is empty", path));
+    }
+    Ok(token.to_string())
+}
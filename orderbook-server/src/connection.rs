@@ -0,0 +1,101 @@
+//! Connections of client sessions: commands read off the socket are forwarded to the server,
+//! and the responses of the server are written back.
+
+use crate::server::Request;
+
+use orderbook_common::{Command, Logon, Response};
+
+use futures::{SinkExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Duration, Instant};
+use tokio_serde::formats::SymmetricalJson;
+use tokio_serde::SymmetricallyFramed;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// Forward commands from a single connection to the server and the responses back, until
+/// either the client hangs up and all its commands have been applied, or the server begins
+/// shutting down, in which case the client is notified first. A command that has already been
+/// read off the socket is always handed over to the server. The server is told when the client
+/// hangs up or misses its heartbeat, but not when the server itself shuts down, so the orders of
+/// the session survive a restart.
+pub async fn serve_connection<R, W>(
+    session: u64,
+    reader: R,
+    writer: W,
+    tx: mpsc::Sender<Request>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let transport = FramedRead::new(reader, LengthDelimitedCodec::new());
+    let mut frames = SymmetricallyFramed::new(transport, SymmetricalJson::<Command>::default());
+    let transport = FramedWrite::new(writer, LengthDelimitedCodec::new());
+    let mut responses = SymmetricallyFramed::new(transport, SymmetricalJson::<Response>::default());
+    let (responses_tx, mut responses_rx) = mpsc::unbounded_channel();
+    // Every request carries a sender of its own, so the responses run out only once the client
+    // has stopped sending and the server is done with all of its requests.
+    let mut responses_tx = Some(responses_tx);
+    let mut heartbeat = None;
+    let mut deadline = None;
+    loop {
+        let timeout = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            frame = frames.try_next(), if responses_tx.is_some() => match frame {
+                Ok(Some(command)) => {
+                    if let Command::Logon(Logon { heartbeat_secs, .. }) = &command {
+                        heartbeat = heartbeat_secs.map(Duration::from_secs);
+                    }
+                    deadline = heartbeat.map(|heartbeat| Instant::now() + heartbeat);
+                    let request = Request::Command {
+                        session,
+                        command: Box::new(command),
+                        responses: responses_tx.clone().unwrap(),
+                    };
+                    if tx.send(request).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    responses_tx = None;
+                    if tx.send(Request::Disconnect { session }).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("failed to read a command: {}", e);
+                    responses_tx = None;
+                    if tx.send(Request::Disconnect { session }).await.is_err() {
+                        break;
+                    }
+                }
+            },
+            _ = timeout, if responses_tx.is_some() => {
+                eprintln!("session {} missed its heartbeat", session);
+                responses_tx = None;
+                if tx.send(Request::Disconnect { session }).await.is_err() {
+                    break;
+                }
+            }
+            response = responses_rx.recv() => match response {
+                Some(response) => {
+                    if responses.send(response).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            _ = shutdown.changed() => {
+                // The client may be gone already, there is nobody to report the error to.
+                let _ = responses.send(Response::Shutdown).await;
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Append-only journal of accepted commands. Every command is written to the journal before it
+//! is applied to the market, so replaying the journal on startup rebuilds exactly the same state.
+//!
+//! The journal is a directory of segment files named after the sequence number of their first
+//! record. Each record is laid out as follows (all integers are little endian):
+//!
+//! ```text
+//! | payload length: u32 | crc32 of sequence and payload: u32 | sequence: u64 | payload |
+//! ```
+//!
+//! where the payload is a JSON encoded command together with the time it was applied at, in
+//! nanoseconds since the Unix epoch.
+
+use orderbook_common::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const HEADER_LEN: usize = 16;
+const SEGMENT_EXTENSION: &str = "journal";
+
+/// Fsync policy of the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fsync {
+    /// Sync after every record.
+    #[default]
+    Always,
+    /// Sync after the given number of records, and whenever the journal is explicitly synced.
+    Batch(u32),
+    /// Never sync, leave it to the operating system.
+    Off,
+}
+
+impl FromStr for Fsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "always" => Ok(Fsync::Always),
+            None if s == "off" => Ok(Fsync::Off),
+            None if s == "batch" => Ok(Fsync::Batch(64)),
+            Some(("batch", n)) => Ok(Fsync::Batch(n.parse()?)),
+            _ => Err(anyhow!("Invalid fsync policy {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JournalConfig {
+    pub dir: PathBuf,
+    pub fsync: Fsync,
+    pub rotate_bytes: u64,
+}
+
+/// A command recovered from the journal.
+#[derive(Debug)]
+pub struct Entry {
+    pub sequence: u64,
+    /// Time the market was advanced to before the command was applied.
+    pub time: Duration,
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload<C> {
+    time: u64,
+    command: C,
+}
+
+pub struct Journal {
+    config: JournalConfig,
+    segment: File,
+    segment_len: u64,
+    next_sequence: u64,
+    unsynced: u32,
+}
+
+impl Journal {
+    /// Open the journal in the configured directory, creating it if necessary, and return it
+    /// together with all the commands recorded so far. A torn record at the very end of the
+    /// journal is the expected result of a crash in the middle of a write, so it is discarded.
+    /// Any other corruption is an error, including a complete record that can't be decoded.
+    pub fn open(config: JournalConfig) -> Result<(Self, Vec<Entry>)> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create journal directory {:?}", config.dir))?;
+        let segments = list_segments(&config.dir)?;
+        let (entries, valid_len) = read_segments(&segments)?;
+        let next_sequence = entries.len() as u64 + 1;
+        let (segment, segment_len) = match segments.last() {
+            Some((_, path)) => {
+                let segment = OpenOptions::new().append(true).open(path)?;
+                if segment.metadata()?.len() != valid_len {
+                    eprintln!(
+                        "Discarding a torn record at the end of the journal segment {:?}",
+                        path
+                    );
+                    segment.set_len(valid_len)?;
+                }
+                (segment, valid_len)
+            }
+            None => (create_segment(&config.dir, next_sequence)?, 0),
+        };
+        let journal = Self {
+            config,
+            segment,
+            segment_len,
+            next_sequence,
+            unsynced: 0,
+        };
+        Ok((journal, entries))
+    }
+
+    /// Append a command applied at the given time to the journal, returning its sequence
+    /// number.
+    pub fn append(&mut self, command: &Command, time: Duration) -> Result<u64> {
+        if self.segment_len >= self.config.rotate_bytes {
+            self.rotate()?;
+        }
+        let sequence = self.next_sequence;
+        let record = encode_record(sequence, command, time)?;
+        self.segment.write_all(&record)?;
+        self.segment_len += record.len() as u64;
+        self.next_sequence += 1;
+        self.unsynced += 1;
+        match self.config.fsync {
+            Fsync::Always => self.sync()?,
+            Fsync::Batch(n) if self.unsynced >= n => self.sync()?,
+            _ => (),
+        }
+        Ok(sequence)
+    }
+
+    /// Sync all the records written so far to disk, unless syncing is turned off.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.config.fsync != Fsync::Off && self.unsynced > 0 {
+            self.segment.sync_data()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Sequence number of the last record in the journal, or zero if it is empty.
+    pub fn last_sequence(&self) -> u64 {
+        self.next_sequence - 1
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.sync()?;
+        self.segment = create_segment(&self.config.dir, self.next_sequence)?;
+        self.segment_len = 0;
+        Ok(())
+    }
+}
+
+/// Read all the commands recorded in the journal in the given directory without opening it
+/// for writing.
+pub fn read(dir: impl AsRef<Path>) -> Result<Vec<Entry>> {
+    let segments = list_segments(dir.as_ref())?;
+    Ok(read_segments(&segments)?.0)
+}
+
+/// Read the entries from the segments, returning them together with the length of the valid
+/// part of the last segment.
+fn read_segments(segments: &[(u64, PathBuf)]) -> Result<(Vec<Entry>, u64)> {
+    let mut entries = Vec::new();
+    let mut next_sequence = 1;
+    let mut valid_len = 0;
+    for (idx, (first_sequence, path)) in segments.iter().enumerate() {
+        if *first_sequence != next_sequence {
+            bail!("Journal segment {:?} does not continue the sequence", path);
+        }
+        let data = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let (mut records, len) = decode_records(&data, next_sequence)
+            .with_context(|| format!("Journal segment {:?} is corrupted", path))?;
+        if len != data.len() && idx + 1 != segments.len() {
+            bail!("Journal segment {:?} is corrupted at offset {}", path, len);
+        }
+        next_sequence += records.len() as u64;
+        entries.append(&mut records);
+        valid_len = len as u64;
+    }
+    Ok((entries, valid_len))
+}
+
+fn segment_path(dir: &Path, first_sequence: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_sequence, SEGMENT_EXTENSION))
+}
+
+fn create_segment(dir: &Path, first_sequence: u64) -> Result<File> {
+    let path = segment_path(dir, first_sequence);
+    OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create journal segment {:?}", path))
+}
+
+/// List journal segments in the directory ordered by their first sequence number.
+fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        let first_sequence = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .with_context(|| format!("Invalid journal segment name {:?}", path))?;
+        segments.push((first_sequence, path));
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn encode_record(sequence: u64, command: &Command, time: Duration) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(&Payload {
+        time: time.as_nanos() as u64,
+        command,
+    })?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(sequence, &payload).to_le_bytes());
+    record.extend_from_slice(&sequence.to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Decode consecutive records starting with the given sequence number. Returns the decoded
+/// entries and the length of the valid prefix of the data.
+fn decode_records(data: &[u8], mut sequence: u64) -> Result<(Vec<Entry>, usize)> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some((entry, len)) = decode_record(&data[offset..], sequence)? {
+        entries.push(entry);
+        offset += len;
+        sequence += 1;
+    }
+    Ok((entries, offset))
+}
+
+/// Decode the record at the start of the data. Returns `None` if the record is incomplete or
+/// fails its checksum, which is how a torn write looks. A record that passes the checksum but
+/// can't be decoded was written wrong, so it is an error.
+fn decode_record(data: &[u8], sequence: u64) -> Result<Option<(Entry, usize)>> {
+    let header = match data.get(..HEADER_LEN) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let record_sequence = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let payload = match data.get(HEADER_LEN..HEADER_LEN + len) {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    if record_sequence != sequence || checksum(record_sequence, payload) != crc {
+        return Ok(None);
+    }
+    let payload = serde_json::from_slice::<Payload<Command>>(payload)
+        .with_context(|| format!("Failed to decode the journal record {}", sequence))?;
+    let entry = Entry {
+        sequence,
+        time: Duration::from_nanos(payload.time),
+        command: payload.command,
+    };
+    Ok(Some((entry, HEADER_LEN + len)))
+}
+
+fn checksum(sequence: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&sequence.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orderbook_common::CancelOrder;
+
+    fn test_config(name: &str, rotate_bytes: u64) -> JournalConfig {
+        let dir =
+            std::env::temp_dir().join(format!("orderbook-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        JournalConfig {
+            dir,
+            fsync: Fsync::Off,
+            rotate_bytes,
+        }
+    }
+
+    fn cancel(user_order_id: u64) -> Command {
+        Command::Cancel(CancelOrder {
+            user_id: 1,
+            user_order_id,
+        })
+    }
+
+    fn user_order_ids(entries: &[Entry]) -> Vec<u64> {
+        entries
+            .iter()
+            .map(|entry| match &entry.command {
+                Command::Cancel(cancel) => cancel.user_order_id,
+                _ => panic!("Unexpected command"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reopen_replays_all_records() {
+        let config = test_config("reopen", 1 << 20);
+        let (mut journal, entries) = Journal::open(config.clone()).unwrap();
+        assert!(entries.is_empty());
+        for id in 1..=3 {
+            assert_eq!(
+                journal
+                    .append(&cancel(id), Duration::from_secs(id))
+                    .unwrap(),
+                id
+            );
+        }
+        drop(journal);
+
+        let (mut journal, entries) = Journal::open(config.clone()).unwrap();
+        assert_eq!(user_order_ids(&entries), vec![1, 2, 3]);
+        assert_eq!(
+            journal.append(&cancel(4), Duration::from_secs(4)).unwrap(),
+            4
+        );
+        drop(journal);
+
+        let (_, entries) = Journal::open(config.clone()).unwrap();
+        assert_eq!(user_order_ids(&entries), vec![1, 2, 3, 4]);
+        let times = entries.iter().map(|entry| entry.time.as_secs());
+        assert_eq!(times.collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let config = test_config("rotation", 1);
+        let (mut journal, _) = Journal::open(config.clone()).unwrap();
+        for id in 1..=3 {
+            journal
+                .append(&cancel(id), Duration::from_secs(id))
+                .unwrap();
+        }
+        drop(journal);
+
+        assert_eq!(list_segments(&config.dir).unwrap().len(), 3);
+        let (_, entries) = Journal::open(config.clone()).unwrap();
+        assert_eq!(user_order_ids(&entries), vec![1, 2, 3]);
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record_is_discarded() {
+        let config = test_config("torn", 1 << 20);
+        let (mut journal, _) = Journal::open(config.clone()).unwrap();
+        journal.append(&cancel(1), Duration::from_secs(1)).unwrap();
+        journal.append(&cancel(2), Duration::from_secs(2)).unwrap();
+        drop(journal);
+
+        let path = segment_path(&config.dir, 1);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let (mut journal, entries) = Journal::open(config.clone()).unwrap();
+        assert_eq!(user_order_ids(&entries), vec![1]);
+        assert_eq!(
+            journal.append(&cancel(3), Duration::from_secs(3)).unwrap(),
+            2
+        );
+        drop(journal);
+
+        let (_, entries) = Journal::open(config.clone()).unwrap();
+        assert_eq!(user_order_ids(&entries), vec![1, 3]);
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_undecodable_record_is_an_error() {
+        let config = test_config("undecodable", 1 << 20);
+        let (mut journal, _) = Journal::open(config.clone()).unwrap();
+        journal.append(&cancel(1), Duration::from_secs(1)).unwrap();
+        drop(journal);
+
+        // A record with a valid checksum but a payload that isn't a command, followed by a
+        // valid record.
+        let payload = b"{}";
+        let mut record = Vec::new();
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(2, payload).to_le_bytes());
+        record.extend_from_slice(&2u64.to_le_bytes());
+        record.extend_from_slice(payload);
+        record.extend(encode_record(3, &cancel(3), Duration::from_secs(3)).unwrap());
+        let path = segment_path(&config.dir, 1);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&record)
+            .unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+
+        assert!(Journal::open(config.clone()).is_err());
+        assert!(read(&config.dir).is_err());
+        // Nothing is truncated, so the records are still there to be looked into.
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+}
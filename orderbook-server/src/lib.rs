@@ -0,0 +1,9 @@
+//! The market server: everything needed to run the market behind the Unix socket, recover it
+//! after a restart and replay its input deterministically.
+
+pub mod config;
+pub mod connection;
+pub mod journal;
+pub mod replay;
+pub mod server;
+pub mod session;
@@ -1,41 +1,62 @@
-use orderbook_common::{Command, SOCKET};
+use orderbook_common::SOCKET;
+use orderbook_server::config::Config;
+use orderbook_server::connection::serve_connection;
+use orderbook_server::server;
 
-use futures::TryStreamExt;
-use tokio::io::AsyncRead;
+use anyhow::Result;
 use tokio::net::UnixListener;
-use tokio::sync::mpsc;
-use tokio_serde::formats::SymmetricalJson;
-use tokio_serde::SymmetricallyFramed;
-use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
-
-mod server;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
+    let config = Config::from_args(std::env::args().skip(1))?;
     let (tx, rx) = mpsc::channel(32);
-    tokio::spawn(async move {
-        server::run(rx).await;
-    });
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(server::run(rx, config));
     let _ = std::fs::remove_file(SOCKET);
     let listener = UnixListener::bind(SOCKET).expect("Failed to bind the unix socket");
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut sessions = 0;
     loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                println!("Accepted new connection");
-                let tx = tx.clone();
-                tokio::spawn(async move {
-                    read_frames(stream, tx).await;
-                });
-            }
-            Err(e) => eprintln!("connection failed: {}", e),
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    println!("Accepted new connection");
+                    sessions += 1;
+                    let session = sessions;
+                    let tx = tx.clone();
+                    let shutdown_rx = shutdown_rx.clone();
+                    tokio::spawn(async move {
+                        let (reader, writer) = stream.into_split();
+                        serve_connection(session, reader, writer, tx, shutdown_rx).await;
+                    });
+                }
+                Err(e) => eprintln!("connection failed: {}", e),
+            },
+            _ = &mut shutdown => break,
         }
     }
+
+    // Stop accepting new connections and ask the existing ones to wind down. Once every
+    // connection has dropped its sender, the server drains whatever is still queued in the
+    // channel and returns.
+    eprintln!("Shutting down");
+    drop(listener);
+    drop(tx);
+    let _ = shutdown_tx.send(true);
+    let result = server.await?;
+    let _ = std::fs::remove_file(SOCKET);
+    result
 }
 
-async fn read_frames<T: AsyncRead + Unpin + Send + 'static>(io: T, tx: mpsc::Sender<Command>) {
-    let transport = FramedRead::new(io, LengthDelimitedCodec::new());
-    let mut frames = SymmetricallyFramed::new(transport, SymmetricalJson::<Command>::default());
-    while let Some(command) = frames.try_next().await.unwrap() {
-        tx.send(command).await.unwrap();
+/// Resolve when the process receives either SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = interrupt.recv() => (),
+        _ = terminate.recv() => (),
     }
 }
@@ -0,0 +1,219 @@
+//! Deterministic replay of commands through an in-process market. The replay produces the same
+//! output format as the server, but without the socket and independently of the tokio runtime,
+//! which makes it a regression harness for the engine.
+
+use crate::journal;
+use crate::server::{advance, apply, format_event};
+
+use orderbook_common::{input, Command};
+use orderbook_engine::prelude::*;
+
+use anyhow::Result;
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of unchanged lines shown around every difference.
+const DIFF_CONTEXT: usize = 2;
+
+pub struct Replay {
+    market: Market<FIFOMatcher>,
+    stamps: ManualClock,
+    sequenced: bool,
+}
+
+impl Replay {
+    /// Create a replay with an empty market, with trading turned on or off.
+    pub fn new(trading: bool) -> Self {
+        let mut market = Market::new(FIFOMatcher);
+        market.set_trading(trading);
+        let stamps = ManualClock::default();
+        market.set_clock(stamps.clone());
+        Self {
+            market,
+            stamps,
+            sequenced: false,
+        }
+    }
+
+    /// Prefix every output line with its sequence number and timestamp and add the exchange ids,
+    /// like the server does with `--sequenced`.
+    pub fn set_sequenced(&mut self, sequenced: bool) {
+        self.sequenced = sequenced;
+    }
+
+    /// Apply a command to the market and return the output lines it produced. Just like the
+    /// server, a flush is followed by an empty line.
+    pub fn apply(&mut self, command: Command) -> Vec<String> {
+        let is_flush = matches!(command, Command::Flush);
+        if let Err(e) = apply(&mut self.market, command) {
+            eprintln!("Failed to apply a command: {}", e);
+        }
+        let mut lines = self.take_lines();
+        if is_flush {
+            lines.push(String::new());
+        }
+        lines
+    }
+
+    /// Advance the market to the given time, as the server did before applying a journaled
+    /// command, and then apply the command. Returns the output lines of both, which are stamped
+    /// with that time just like they were by the server.
+    pub fn apply_at(&mut self, time: Duration, command: Command) -> Vec<String> {
+        advance(&mut self.market, &self.stamps, time);
+        let mut lines = self.take_lines();
+        lines.extend(self.apply(command));
+        lines
+    }
+
+    fn take_lines(&mut self) -> Vec<String> {
+        self.market
+            .take_events()
+            .iter()
+            .map(|event| format_event(event, self.sequenced))
+            .collect()
+    }
+
+    /// Apply all the commands in order and return the output lines they produced.
+    pub fn run(&mut self, commands: impl IntoIterator<Item = Command>) -> Vec<String> {
+        commands
+            .into_iter()
+            .flat_map(|command| self.apply(command))
+            .collect()
+    }
+}
+
+/// Load commands either from a CSV file or from a journal directory, together with the times
+/// they are applied at. Commands from a CSV file carry no time, so they are all applied at time
+/// zero.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<(Duration, Command)>> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        Ok(journal::read(path)?
+            .into_iter()
+            .map(|entry| (entry.time, entry.command))
+            .collect())
+    } else {
+        Ok(input::read_file(path)?
+            .into_iter()
+            .map(|command| (Duration::ZERO, command))
+            .collect())
+    }
+}
+
+/// Normalize output lines for comparison: comments and empty lines are dropped, and fields are
+/// separated by exactly one space after the comma.
+pub fn normalize<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Vec<String> {
+    lines
+        .into_iter()
+        .map(|line| {
+            line.as_ref()
+                .split(',')
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Compare the expected lines with the actual ones. Returns `None` if they are equal, or a
+/// readable line diff otherwise, where missing lines are marked with `-` and unexpected lines
+/// are marked with `+`.
+pub fn diff(expected: &[String], actual: &[String]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    // Longest common subsequence of the lines, computed from the end so that the diff can be
+    // produced walking forward.
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            ops.push((' ', &expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', &expected[i]));
+            i += 1;
+        } else {
+            ops.push(('+', &actual[j]));
+            j += 1;
+        }
+    }
+
+    let changed = ops.iter().map(|(op, _)| *op != ' ').collect::<Vec<_>>();
+    let near_change = |idx: usize| {
+        let from = idx.saturating_sub(DIFF_CONTEXT);
+        let to = (idx + DIFF_CONTEXT + 1).min(changed.len());
+        changed[from..to].iter().any(|&c| c)
+    };
+    let mut output = String::new();
+    let mut skipped = false;
+    for (idx, (op, line)) in ops.iter().enumerate() {
+        if near_change(idx) {
+            output.push_str(&format!("{} {}\n", op, line));
+            skipped = false;
+        } else if !skipped {
+            output.push_str("  ...\n");
+            skipped = true;
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_normalize() {
+        let normalized = normalize(["# comment", "", "A, 2, 102 ", "B,S, 11,100"]);
+        assert_eq!(normalized, lines(&["A, 2, 102", "B, S, 11, 100"]));
+    }
+
+    #[test]
+    fn test_diff() {
+        let expected = lines(&["A, 1, 1", "B, B, 10, 100", "A, 1, 2", "B, S, 12, 100"]);
+        assert_eq!(diff(&expected, &expected), None);
+        let actual = lines(&["A, 1, 1", "B, B, 10, 100", "R, 1, 2"]);
+        assert_eq!(
+            diff(&expected, &actual).unwrap(),
+            "  A, 1, 1\n  B, B, 10, 100\n- A, 1, 2\n- B, S, 12, 100\n+ R, 1, 2\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_side_is_refused() {
+        let command = input::parse_line("N, 1, IBM, 10, 100, X, 1")
+            .unwrap()
+            .unwrap();
+        match crate::server::validate(&command).map_err(|rejection| *rejection) {
+            Err(orderbook_common::Response::Rejected {
+                user_id: 1,
+                user_order_id: 1,
+                reason,
+            }) => assert_eq!(reason, "invalid side 'X'"),
+            other => panic!("Unexpected validation {:?}", other),
+        }
+        assert!(Replay::new(false).apply(command).is_empty());
+
+        let command = input::parse_line("M, 1, IBM, X").unwrap().unwrap();
+        assert!(crate::server::validate(&command).is_err());
+        assert!(Replay::new(false).apply(command).is_empty());
+    }
+}
@@ -1,52 +1,711 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use orderbook_common::{CancelOrder, Command, NewOrder};
+use orderbook_common::{
+    self as common, AdminCommand, AdminRequest, BracketOrder, CancelOrder, Command, Deposit,
+    MassCancel, NewOrder, OcoOrder, PhaseChange, PositionQuery, Response,
+};
 use orderbook_engine::prelude::*;
 
-use string_interner::StringInterner;
+use crate::config::Config;
+use crate::journal::Journal;
+use crate::session::{self, Sessions};
+
+use anyhow::{bail, Context, Result};
 use tokio::sync::mpsc;
 
+/// How often the market is advanced to the current time while no commands come in.
+const CLOCK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A message from the connection of a client session to the server.
+#[derive(Debug)]
+pub enum Request {
+    /// A command received from the client, together with the channel to send the responses to
+    /// the client through.
+    Command {
+        session: u64,
+        command: Box<Command>,
+        responses: mpsc::UnboundedSender<Response>,
+    },
+    /// The connection has dropped or its heartbeat has timed out.
+    Disconnect { session: u64 },
+}
+
 // Using mutex for synchornizing access to the orderbook in HFT is a bad idea. Ideally,
 // the markey, the orderbooks and all internal datastructures should be made lock-free.
 // It is possible, e.g. to use a lock-free skip list instead of a BTreeMap for price
 // levels, but rust ecosystems lacks a stable crate with a good api for that purpose.
-pub async fn run(mut rx: mpsc::Receiver<Command>) {
-    let mut si = StringInterner::default();
-    let market = Arc::new(Mutex::new(Market::new(FIFOMatcher)));
-    while let Some(command) = rx.recv().await {
-        match command {
-            Command::New(order) => {
-                let order = limit_order(order, &mut si);
-                market.lock().unwrap().add(order);
-            }
-            Command::Cancel(CancelOrder {
-                user_id,
-                user_order_id,
-            }) => {
-                market.lock().unwrap().cancel(user_id, user_order_id);
+pub async fn run(mut rx: mpsc::Receiver<Request>, config: Config) -> Result<()> {
+    let (mut market, mut position) = match &config.snapshot {
+        Some(path) if path.exists() => {
+            let snapshot = Snapshot::load(path)
+                .with_context(|| format!("Failed to load the snapshot {:?}", path))?;
+            let position = snapshot.position;
+            (Market::restore(FIFOMatcher, snapshot), position)
+        }
+        _ => (Market::new(FIFOMatcher), 0),
+    };
+    // Events are stamped with the time the market is advanced to, which is journaled along with
+    // the commands, so that replaying the journal stamps them just the same.
+    let stamps = ManualClock::default();
+    market.set_clock(stamps.clone());
+    market.set_trading(config.trading);
+    market.set_reject_reused_ids(config.reject_reused_ids);
+    market.set_circuit_breaker(config.breaker);
+    let cash = config.funding.as_ref().map(|cash| market.intern(cash));
+    market.set_funding(cash);
+    market.set_default_risk_limits(config.risk.default);
+    for (&user_id, &limits) in &config.risk.users {
+        market.set_risk_limits(user_id, limits);
+    }
+    market.set_default_fee_rates(config.fees.default);
+    for (&user_id, &tier) in &config.fees.tiers {
+        market.set_fee_tier(user_id, tier);
+    }
+    for rule in &config.fees.rates {
+        let symbol = rule.symbol.as_ref().map(|symbol| market.intern(symbol));
+        market.set_fee_rates(symbol, rule.tier, rule.rates);
+    }
+    // Time the market was last advanced to. It never goes back, even if the system time does,
+    // so that replaying the journaled times makes the same scheduled changes between the same
+    // commands.
+    let mut time = Duration::ZERO;
+    let mut journal = match config.journal {
+        Some(journal_config) => {
+            let (journal, entries) = Journal::open(journal_config)?;
+            if journal.last_sequence() < position {
+                bail!("The snapshot is ahead of the journal");
+            }
+            // Commands up to the snapshot position are already reflected in the market.
+            for entry in entries
+                .into_iter()
+                .filter(|entry| entry.sequence > position)
+            {
+                // Only valid commands are journaled, so they apply just like the first time.
+                time = time.max(entry.time);
+                advance(&mut market, &stamps, time);
+                if let Err(e) = apply(&mut market, entry.command) {
+                    eprintln!("Failed to replay a command: {}", e);
+                }
+            }
+            // The events of the replayed commands have been published before the restart.
+            market.take_events();
+            position = journal.last_sequence();
+            Some(journal)
+        }
+        None => None,
+    };
+    let mut ledger = config
+        .ledger
+        .as_deref()
+        .map(LedgerExport::open)
+        .transpose()?;
+    if let Some(ledger) = ledger.as_mut() {
+        // The entries recorded again while replaying the journal have been exported before the
+        // restart, except for those of the commands applied right before it stopped.
+        let exported = ledger.last_sequence;
+        let mut entries = market.take_ledger_entries();
+        entries.retain(|entry| entry.sequence > exported);
+        ledger.append(&market, &entries)?;
+    }
+    let market = Arc::new(Mutex::new(market));
+    let mut since_snapshot = 0;
+    let mut clock = tokio::time::interval(CLOCK_INTERVAL);
+    let mut sessions = Sessions::default();
+    loop {
+        let request = tokio::select! {
+            request = rx.recv() => match request {
+                Some(request) => request,
+                None => break,
+            },
+            // Scheduled changes of trading phases are due even if no commands come in.
+            _ = clock.tick() => {
+                time = time.max(now());
+                let mut market = market.lock().unwrap();
+                advance(&mut market, &stamps, time);
+                for event in market.take_events() {
+                    print_event(&event, config.sequenced);
+                }
+                export_ledger(&mut market, ledger.as_mut())?;
+                continue;
             }
-            Command::Flush => {
-                market.lock().unwrap().clear();
+        };
+        let (session, commands, responses) = match request {
+            Request::Command {
+                session,
+                command,
+                responses,
+            } => match *command {
+                // Queries and session messages don't change the market, so they are handled
+                // right away and not journaled.
+                Command::QueryPositions(PositionQuery { user_id }) => {
+                    let response = positions(&market.lock().unwrap(), user_id);
+                    let _ = responses.send(response);
+                    continue;
+                }
+                Command::Logon(logon) => {
+                    sessions.logon(session, &logon);
+                    continue;
+                }
+                Command::Heartbeat => continue,
+                // Admin commands are authenticated before they are journaled, and the token is
+                // left out of the journal.
+                Command::Admin(AdminRequest { token, command }) => {
+                    if !authenticate(config.admin_token.as_deref(), &token) {
+                        eprintln!("Refused an unauthenticated admin command {:?}", command);
+                        let _ = responses.send(Response::AdminRejected {
+                            command,
+                            reason: "not authenticated".to_string(),
+                        });
+                        continue;
+                    }
+                    let command = Command::Admin(AdminRequest {
+                        token: String::new(),
+                        command,
+                    });
+                    (None, vec![command], Some(responses))
+                }
+                command => (Some(session), vec![command], Some(responses)),
+            },
+            // The cancellations go through the journal like any other command, so that a replay
+            // doesn't depend on when the connections dropped. Orders that are gone already
+            // aren't cancelled again.
+            Request::Disconnect { session } => {
+                let market = market.lock().unwrap();
+                let cancels = sessions
+                    .close(session)
+                    .into_iter()
+                    .filter(|&(user_id, user_order_id)| market.contains(user_id, user_order_id))
+                    .map(|(user_id, user_order_id)| {
+                        Command::Cancel(CancelOrder {
+                            user_id,
+                            user_order_id,
+                        })
+                    })
+                    .collect();
+                (None, cancels, None)
+            }
+        };
+        for command in commands {
+            if let Err(rejection) = validate(&command) {
+                eprintln!("Rejected an invalid command {:?}", command);
+                if let Some(responses) = &responses {
+                    let _ = responses.send(*rejection);
+                }
+                continue;
+            }
+            // The command must be in the journal before it affects the market, otherwise it
+            // would be lost after a crash. Its time goes along, so that a replay advances the
+            // market exactly as far before applying it.
+            time = time.max(now());
+            if let Some(journal) = journal.as_mut() {
+                position = journal.append(&command, time)?;
+            }
+            let is_flush = matches!(command, Command::Flush);
+            let admin = match &command {
+                Command::Admin(request) => Some(request.command.clone()),
+                _ => None,
+            };
+            // The orders of the command are only the session's once the market has accepted
+            // them.
+            let entered = session.map(|session| (session, session::order_ids(&command)));
+            let mut accepted = false;
+            let mut market = market.lock().unwrap();
+            advance(&mut market, &stamps, time);
+            let result = apply(&mut market, command);
+            if let Err(e) = &result {
+                eprintln!("Failed to apply a command: {}", e);
+            }
+            for event in market.take_events() {
+                print_event(&event, config.sequenced);
+                if let (
+                    Event::Accepted {
+                        user_id,
+                        user_order_id,
+                        ..
+                    },
+                    Some((_, orders)),
+                ) = (&event.event, &entered)
+                {
+                    accepted |= orders.contains(&(*user_id, *user_order_id));
+                }
+                if let (Some(response), Some(responses)) =
+                    (client_response(&market, event.event), &responses)
+                {
+                    // The client may be gone already, the event is in the output anyway.
+                    let _ = responses.send(response);
+                }
+            }
+            if let (Some((session, orders)), true) = (entered, accepted) {
+                sessions.record(session, &orders);
+            }
+            if is_flush {
                 println!();
             }
+            export_ledger(&mut market, ledger.as_mut())?;
+            if let (Some(command), Some(responses)) = (admin, &responses) {
+                let _ = responses.send(match result {
+                    Ok(()) => Response::AdminAck { command },
+                    Err(e) => Response::AdminRejected {
+                        command,
+                        reason: e.to_string(),
+                    },
+                });
+            }
+            since_snapshot += 1;
+            if let (Some(path), Some(interval)) = (&config.snapshot, config.snapshot_interval) {
+                if since_snapshot >= interval {
+                    save_snapshot(&market, path, position)?;
+                    since_snapshot = 0;
+                }
+            }
         }
     }
+    // The channel is closed only after every connection is gone and all the queued commands
+    // have been applied, so whatever is left in the buffers is the final state of the market.
+    if let Some(journal) = journal.as_mut() {
+        journal.sync()?;
+    }
+    if let Some(path) = &config.snapshot {
+        save_snapshot(&market.lock().unwrap(), path, position)?;
+    }
+    if let Some(path) = &config.billing {
+        save_billing(&market.lock().unwrap(), path)?;
+    }
+    std::io::stdout().flush()?;
+    Ok(())
+}
+
+/// Advance the market to the given time and stamp the events and trades that follow with it.
+pub fn advance(market: &mut Market<FIFOMatcher>, stamps: &ManualClock, time: Duration) {
+    stamps.set(time.as_nanos() as u64);
+    market.advance(time);
+}
+
+/// Print an event in the published format.
+fn print_event(event: &SequencedEvent, sequenced: bool) {
+    println!("{}", format_event(event, sequenced));
+}
+
+/// Format an event in the published format, prefixed with its sequence number and timestamp if
+/// asked to.
+pub fn format_event(event: &SequencedEvent, sequenced: bool) -> String {
+    if sequenced {
+        format!("{}, {}, {:#}", event.sequence, event.timestamp, event)
+    } else {
+        event.to_string()
+    }
+}
+
+/// Get the response to the client whose command led to the event, if the event concerns the
+/// orders of the client.
+fn client_response(market: &Market<FIFOMatcher>, event: Event) -> Option<Response> {
+    match event {
+        Event::Accepted {
+            user_id,
+            user_order_id,
+            order_id,
+        } => Some(Response::Accepted {
+            user_id,
+            user_order_id,
+            order_id,
+        }),
+        Event::Rejected {
+            user_id,
+            user_order_id,
+            reason,
+        } => Some(Response::Rejected {
+            user_id,
+            user_order_id,
+            reason: reason.to_string(),
+        }),
+        Event::Trade(trade) => Some(Response::Trade {
+            trade_id: trade.trade_id,
+            symbol: market
+                .symbol_name(trade.symbol)
+                .unwrap_or_default()
+                .to_string(),
+            price: trade.price.0,
+            quantity: trade.quantity,
+            fee_buy: trade.fee_buy,
+            fee_sell: trade.fee_sell,
+            timestamp: trade.timestamp,
+            user_id_buy: trade.user_id_buy,
+            user_order_id_buy: trade.user_order_id_buy,
+            order_id_buy: trade.order_id_buy,
+            user_id_sell: trade.user_id_sell,
+            user_order_id_sell: trade.user_order_id_sell,
+            order_id_sell: trade.order_id_sell,
+            aggressor: trade.aggressor.map(encode_side),
+        }),
+        _ => None,
+    }
+}
+
+/// Get the wall clock time the market is advanced to.
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn save_snapshot(market: &Market<FIFOMatcher>, path: &Path, position: u64) -> Result<()> {
+    market
+        .snapshot(position)
+        .save(path)
+        .with_context(|| format!("Failed to save the snapshot {:?}", path))
 }
 
-fn limit_order(order: NewOrder, si: &mut StringInterner) -> Order {
-    let side = decode_side(order.side);
-    Order::with_ids(order.user_id, order.user_order_id).limit_order(
-        side,
-        si.get_or_intern(order.symbol),
-        order.price as f64,
-        order.quantity,
-    )
+/// Export the fee totals of the users as CSV, one line per user and security.
+fn save_billing(market: &Market<FIFOMatcher>, path: &Path) -> Result<()> {
+    let mut csv = String::from("user_id, symbol, quantity, value, fees, rebates\n");
+    for (user_id, symbol, total) in market.fee_totals() {
+        csv.push_str(&format!(
+            "{}, {}, {}, {}, {}, {}\n",
+            user_id,
+            market.symbol_name(symbol).unwrap_or_default(),
+            total.quantity,
+            total.value,
+            total.fees,
+            total.rebates
+        ));
+    }
+    std::fs::write(path, csv).with_context(|| format!("Failed to export the billing {:?}", path))
+}
+
+/// Ledger entries exported as CSV, appended to the file as they are recorded.
+struct LedgerExport {
+    file: File,
+    // Sequence number of the last entry in the file when it was opened.
+    last_sequence: u64,
+}
+
+impl LedgerExport {
+    /// Open the export for appending, finding the last entry exported so far.
+    fn open(path: &Path) -> Result<Self> {
+        let csv = match std::fs::read_to_string(path) {
+            Ok(csv) => csv,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read the ledger {:?}", path))
+            }
+        };
+        let last_sequence = csv
+            .lines()
+            .rev()
+            .find_map(|line| line.split(',').next()?.trim().parse().ok())
+            .unwrap_or(0);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open the ledger {:?}", path))?;
+        if csv.is_empty() {
+            file.write_all(b"sequence, asset, debit, credit, amount\n")?;
+        }
+        Ok(Self {
+            file,
+            last_sequence,
+        })
+    }
+
+    /// Append the entries, one line per entry.
+    fn append(&mut self, market: &Market<FIFOMatcher>, entries: &[LedgerEntry]) -> Result<()> {
+        let mut csv = String::new();
+        for entry in entries {
+            csv.push_str(&format!(
+                "{}, {}, {}, {}, {}\n",
+                entry.sequence,
+                market.symbol_name(entry.asset).unwrap_or_default(),
+                entry.debit,
+                entry.credit,
+                entry.amount
+            ));
+        }
+        self.file
+            .write_all(csv.as_bytes())
+            .context("Failed to export the ledger")
+    }
+}
+
+/// Take the new ledger entries off the market and export them, if asked to.
+fn export_ledger(
+    market: &mut Market<FIFOMatcher>,
+    ledger: Option<&mut LedgerExport>,
+) -> Result<()> {
+    let entries = market.take_ledger_entries();
+    match ledger {
+        Some(ledger) => ledger.append(market, &entries),
+        None => Ok(()),
+    }
+}
+
+/// Report the positions of a user with their profit and loss.
+fn positions(market: &Market<FIFOMatcher>, user_id: u64) -> Response {
+    let positions = market
+        .position_reports(user_id)
+        .into_iter()
+        .map(|report| common::Position {
+            symbol: market
+                .symbol_name(report.symbol)
+                .unwrap_or_default()
+                .to_string(),
+            quantity: report.position.quantity,
+            average_cost: report.position.average_cost,
+            realized_pnl: report.position.realized_pnl,
+            mark_price: report.mark_price.map(|price| price.0),
+            unrealized_pnl: report.unrealized_pnl,
+        })
+        .collect();
+    Response::Positions { user_id, positions }
+}
+
+/// Check that the command can be decoded, so that a command the market can't make sense of is
+/// rejected before it gets into the journal, instead of failing again on every replay.
+pub fn validate(command: &Command) -> Result<(), Box<Response>> {
+    let orders = match command {
+        Command::New(order) => vec![order],
+        Command::Oco(OcoOrder { first, second, .. }) => vec![first, second],
+        Command::Bracket(BracketOrder {
+            entry,
+            take_profit,
+            stop_loss,
+            ..
+        }) => vec![entry, take_profit, stop_loss],
+        Command::MassCancel(MassCancel {
+            user_id,
+            side: Some(side),
+            ..
+        }) => {
+            return decode_side(*side).map(drop).map_err(|e| {
+                Box::new(Response::Rejected {
+                    user_id: user_id.unwrap_or_default(),
+                    user_order_id: 0,
+                    reason: e.to_string(),
+                })
+            })
+        }
+        _ => Vec::new(),
+    };
+    for order in orders {
+        if let Err(e) = decode_side(order.side) {
+            return Err(Box::new(Response::Rejected {
+                user_id: order.user_id,
+                user_order_id: order.user_order_id,
+                reason: e.to_string(),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Apply a single command to the market. Queries leave the market as it is. A command that
+/// can't be decoded, or an admin command the market can't carry out in its current state, is
+/// refused before anything changes.
+pub fn apply(market: &mut Market<FIFOMatcher>, command: Command) -> Result<()> {
+    match command {
+        Command::New(order) => {
+            let order = new_order(order, market)?;
+            market.add(order);
+        }
+        Command::Cancel(CancelOrder {
+            user_id,
+            user_order_id,
+        }) => {
+            market.cancel(user_id, user_order_id);
+        }
+        Command::Flush => market.clear(),
+        Command::Oco(OcoOrder {
+            first,
+            second,
+            cancel_on,
+        }) => {
+            let first = new_order(first, market)?;
+            let second = new_order(second, market)?;
+            market.add_oco(first, second, oco_cancel(cancel_on));
+        }
+        Command::Bracket(BracketOrder {
+            entry,
+            take_profit,
+            stop_loss,
+            cancel_on,
+        }) => {
+            let entry = new_order(entry, market)?;
+            let take_profit = new_order(take_profit, market)?;
+            let stop_loss = new_order(stop_loss, market)?;
+            market.add_bracket(entry, take_profit, stop_loss, oco_cancel(cancel_on));
+        }
+        Command::MassCancel(MassCancel {
+            user_id,
+            symbol,
+            side,
+        }) => {
+            let side = side.map(decode_side).transpose()?;
+            let filter = orderbook_engine::prelude::MassCancel {
+                user_id,
+                symbol: symbol.map(|symbol| market.intern(&symbol)),
+                side,
+            };
+            market.mass_cancel(filter);
+        }
+        Command::Admin(AdminRequest { command, .. }) => apply_admin(market, command)?,
+        Command::QueryPositions(_) | Command::Logon(_) | Command::Heartbeat => (),
+    }
+    Ok(())
+}
+
+/// Check the token of an admin command against the configured one. The comparison takes the
+/// same time wherever the tokens differ, so that the token can't be guessed byte by byte from
+/// the response times. Without a configured token, nothing is authenticated.
+fn authenticate(expected: Option<&str>, token: &str) -> bool {
+    let expected = match expected {
+        Some(expected) => expected.as_bytes(),
+        None => return false,
+    };
+    let token = token.as_bytes();
+    let difference = expected
+        .iter()
+        .zip(token)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    expected.len() == token.len() && difference == 0
+}
+
+/// Apply an authenticated admin command to the market.
+fn apply_admin(market: &mut Market<FIFOMatcher>, command: AdminCommand) -> Result<()> {
+    match command {
+        AdminCommand::HaltSymbol(name) => {
+            let symbol = market.intern(&name);
+            if market.set_phase(symbol, Phase::Halted).is_none() {
+                bail!("{} can't be halted in its current phase", name);
+            }
+        }
+        AdminCommand::ResumeSymbol(name) => {
+            let symbol = market.intern(&name);
+            if market.set_phase(symbol, Phase::Continuous).is_none() {
+                bail!("{} can't be resumed in its current phase", name);
+            }
+        }
+        AdminCommand::HaltMarket if market.is_halted() => bail!("the market is already halted"),
+        AdminCommand::HaltMarket => market.set_halted(true),
+        AdminCommand::ResumeMarket if !market.is_halted() => bail!("the market isn't halted"),
+        AdminCommand::ResumeMarket => market.set_halted(false),
+        AdminCommand::KillUser(user_id) => {
+            market.disable_user(user_id);
+        }
+        AdminCommand::EnableUser(user_id) if !market.is_disabled(user_id) => {
+            bail!("user {} isn't disabled", user_id)
+        }
+        AdminCommand::EnableUser(user_id) => market.enable_user(user_id),
+        AdminCommand::Deposit(Deposit {
+            user_id,
+            asset,
+            amount,
+        }) => {
+            let asset = market.intern(&asset);
+            market.deposit(user_id, asset, amount as f64);
+        }
+        AdminCommand::SetPhase(PhaseChange {
+            symbol: name,
+            phase,
+        }) => {
+            let symbol = market.intern(&name);
+            let phase = match phase {
+                common::Phase::PreOpen => Phase::PreOpen,
+                common::Phase::Auction => Phase::Auction,
+                common::Phase::Continuous => Phase::Continuous,
+                common::Phase::Halted => Phase::Halted,
+                common::Phase::Closed => Phase::Closed,
+            };
+            if market.set_phase(symbol, phase).is_none() {
+                bail!("{} can't change to {} in its current phase", name, phase);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build an engine order from a wire message. A zero price stands for a market order.
+fn new_order(order: NewOrder, market: &mut Market<FIFOMatcher>) -> Result<Order> {
+    let side = decode_side(order.side)?;
+    let symbol = market.intern(&order.symbol);
+    let mut builder = Order::with_ids(order.user_id, order.user_order_id);
+    if let Some(display_quantity) = order.display_quantity {
+        builder = builder.display_quantity(display_quantity);
+    }
+    if let Some(post_only) = order.post_only {
+        builder = builder.post_only(match post_only {
+            common::PostOnly::Reject => PostOnly::Reject,
+            common::PostOnly::Reprice => PostOnly::Reprice,
+        });
+    }
+    if let Some(min_quantity) = order.min_quantity {
+        builder = builder.min_quantity(min_quantity);
+    }
+    if order.all_or_none {
+        builder = builder.all_or_none();
+    }
+    if order.hidden {
+        builder = builder.hidden();
+    }
+    if let Some(peg) = order.peg {
+        let peg = Peg {
+            reference: match peg.reference {
+                common::PegReference::Primary => PegReference::Primary,
+                common::PegReference::Market => PegReference::Market,
+                common::PegReference::Midpoint => PegReference::Midpoint,
+            },
+            offset: (peg.offset as f64).into(),
+            limit: match order.price {
+                0 => None,
+                price => Some((price as f64).into()),
+            },
+        };
+        return Ok(builder.pegged_order(side, symbol, peg, order.quantity));
+    }
+    if let Some(trail) = order.trail {
+        let trail = match trail {
+            common::Trail::Amount(amount) => Trail::Amount((amount as f64).into()),
+            common::Trail::Percent(percent) => Trail::Percent(percent),
+        };
+        return Ok(builder.trailing_stop_order(side, symbol, trail, order.quantity));
+    }
+    Ok(match (order.stop_price, order.price) {
+        (None, 0) => builder.market_order(side, symbol, order.quantity),
+        (None, price) => builder.limit_order(side, symbol, price as f64, order.quantity),
+        (Some(stop_price), 0) => {
+            builder.stop_order(side, symbol, stop_price as f64, order.quantity)
+        }
+        (Some(stop_price), price) => builder.stop_limit_order(
+            side,
+            symbol,
+            stop_price as f64,
+            price as f64,
+            order.quantity,
+        ),
+    })
+}
+
+fn encode_side(side: Side) -> char {
+    match side {
+        Side::Bid => 'B',
+        Side::Ask => 'S',
+    }
+}
+
+fn oco_cancel(cancel_on: common::OcoCancel) -> OcoCancel {
+    match cancel_on {
+        common::OcoCancel::PartialFill => OcoCancel::PartialFill,
+        common::OcoCancel::Fill => OcoCancel::Fill,
+    }
 }
 
-fn decode_side(side: char) -> Side {
+fn decode_side(side: char) -> Result<Side> {
     match side {
-        'B' => Side::Bid,
-        'S' => Side::Ask,
-        _ => panic!("Invalid side"),
+        'B' => Ok(Side::Bid),
+        'S' => Ok(Side::Ask),
+        _ => bail!("invalid side {:?}", side),
     }
 }
@@ -0,0 +1,113 @@
+//! Client sessions: the connections to the server, the orders that came through them and
+//! whether those orders are cancelled once the connection is gone.
+
+use orderbook_common::{BracketOrder, Command, Logon, OcoOrder};
+
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Default)]
+struct Session {
+    cancel_on_disconnect: bool,
+    orders: BTreeSet<(u64, u64)>,
+}
+
+/// Sessions keeps track of the open sessions by their ids, and of the session every order was
+/// entered through by the ids `(user_id, user_order_id)` of the order. A user may trade through
+/// several sessions at once, each of which only owns the orders it has entered.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    sessions: HashMap<u64, Session>,
+    owners: HashMap<(u64, u64), u64>,
+}
+
+impl Sessions {
+    /// Apply the options of the logon to the session.
+    pub fn logon(&mut self, session: u64, logon: &Logon) {
+        self.sessions
+            .entry(session)
+            .or_default()
+            .cancel_on_disconnect = logon.cancel_on_disconnect;
+    }
+
+    /// Remember the orders entered through the session. An order whose ids have been used before,
+    /// through whichever session, belongs to this session from now on.
+    pub fn record(&mut self, session: u64, orders: &[(u64, u64)]) {
+        for &ids in orders {
+            match self.owners.insert(ids, session) {
+                Some(owner) if owner != session => {
+                    if let Some(owner) = self.sessions.get_mut(&owner) {
+                        owner.orders.remove(&ids);
+                    }
+                }
+                _ => (),
+            }
+            self.sessions.entry(session).or_default().orders.insert(ids);
+        }
+    }
+
+    /// Close the session, returning the ids of the orders entered through it if the session has
+    /// opted in to cancel on disconnect. Some of them may have been filled or cancelled already.
+    pub fn close(&mut self, session: u64) -> Vec<(u64, u64)> {
+        let session = match self.sessions.remove(&session) {
+            Some(session) => session,
+            None => return Vec::new(),
+        };
+        for ids in &session.orders {
+            self.owners.remove(ids);
+        }
+        match session.cancel_on_disconnect {
+            true => session.orders.into_iter().collect(),
+            false => Vec::new(),
+        }
+    }
+}
+
+/// Get the ids of the orders the command enters, including the take-profit and stop-loss orders
+/// a bracket order adds once its entry is filled.
+pub fn order_ids(command: &Command) -> Vec<(u64, u64)> {
+    match command {
+        Command::New(order) => vec![(order.user_id, order.user_order_id)],
+        Command::Oco(OcoOrder { first, second, .. }) => vec![
+            (first.user_id, first.user_order_id),
+            (second.user_id, second.user_order_id),
+        ],
+        Command::Bracket(BracketOrder {
+            entry,
+            take_profit,
+            stop_loss,
+            ..
+        }) => vec![
+            (entry.user_id, entry.user_order_id),
+            (take_profit.user_id, take_profit.user_order_id),
+            (stop_loss.user_id, stop_loss.user_order_id),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_on_disconnect() {
+        let mut sessions = Sessions::default();
+        let logon = Logon {
+            cancel_on_disconnect: true,
+            heartbeat_secs: None,
+        };
+        sessions.logon(1, &logon);
+        sessions.logon(3, &logon);
+        for ids in [(7, 2), (3, 1), (7, 1)] {
+            sessions.record(1, &[ids]);
+            sessions.record(2, &[(ids.0, ids.1 + 10)]);
+        }
+        // The ids of an order of session 1 reused through session 3.
+        sessions.record(3, &[(7, 2)]);
+        assert_eq!(sessions.close(1), vec![(3, 1), (7, 1)]);
+        assert!(sessions.close(1).is_empty());
+        // The other session hasn't opted in.
+        assert!(sessions.close(2).is_empty());
+        assert_eq!(sessions.close(3), vec![(7, 2)]);
+    }
+}
@@ -0,0 +1,129 @@
+//! Conformance of the engine with the published input and output files. Every expected output
+//! scenario is matched with its input scenario, replayed through a fresh market and compared
+//! line by line. Scenarios in the bonus section of the output are produced with trading on, the
+//! rest with trading off.
+
+use orderbook_common::{input, Command};
+use orderbook_server::replay::{self, Replay};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+struct Expected {
+    name: String,
+    input: u32,
+    trading: bool,
+    lines: Vec<String>,
+}
+
+fn asset(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../assets")
+        .join(name);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e))
+}
+
+/// Extract the scenario number from a header like `#name: scenario 13 - input scenario 5`,
+/// returning the number of the scenario itself and the number of the input scenario it refers
+/// to, which is the same unless stated otherwise.
+fn parse_header(line: &str) -> Option<(u32, u32)> {
+    let name = line.strip_prefix("#name:")?;
+    let numbers = name
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|word| word.parse().ok())
+        .collect::<Vec<u32>>();
+    match numbers[..] {
+        [number] => Some((number, number)),
+        [number, input, ..] => Some((number, input)),
+        _ => None,
+    }
+}
+
+/// Split the input file into scenarios delimited by `#name:` headers and terminated by flushes.
+fn input_scenarios() -> BTreeMap<u32, Vec<Command>> {
+    let mut scenarios = BTreeMap::new();
+    let mut current = None;
+    for line in asset("input_file.csv").lines() {
+        if let Some((number, _)) = parse_header(line) {
+            current = Some(number);
+            scenarios.insert(number, Vec::new());
+            continue;
+        }
+        let command = input::parse_line(line).unwrap();
+        if let (Some(number), Some(command)) = (current, command) {
+            let is_flush = matches!(command, Command::Flush);
+            scenarios.get_mut(&number).unwrap().push(command);
+            if is_flush {
+                current = None;
+            }
+        }
+    }
+    scenarios
+}
+
+/// Split the output file into scenarios delimited by `#name:` headers. Everything after the
+/// bonus section marker is expected to be produced with trading on.
+fn expected_scenarios() -> Vec<Expected> {
+    let mut scenarios = Vec::<Expected>::new();
+    let mut trading = false;
+    for line in asset("output_file.csv").lines() {
+        if line.starts_with("# Bonus") {
+            trading = true;
+        } else if let Some((_, input)) = parse_header(line) {
+            scenarios.push(Expected {
+                name: line.trim_start_matches("#name:").trim().to_string(),
+                input,
+                trading,
+                lines: Vec::new(),
+            });
+        } else if let Some(scenario) = scenarios.last_mut() {
+            scenario.lines.push(line.to_string());
+        }
+    }
+    for scenario in &mut scenarios {
+        scenario.lines = replay::normalize(&scenario.lines);
+    }
+    scenarios
+}
+
+#[test]
+fn test_every_input_scenario_is_expected() {
+    let inputs = input_scenarios();
+    let expected = expected_scenarios();
+    assert_eq!(inputs.len(), 12);
+    for number in inputs.keys() {
+        assert!(
+            expected.iter().any(|scenario| scenario.input == *number),
+            "No expected output for input scenario {}",
+            number
+        );
+    }
+}
+
+#[test]
+fn test_scenarios_conform_to_expected_output() {
+    let inputs = input_scenarios();
+    let mut failures = Vec::new();
+    for scenario in expected_scenarios() {
+        let commands = inputs
+            .get(&scenario.input)
+            .unwrap_or_else(|| panic!("No input for {}", scenario.name))
+            .clone();
+        let actual = replay::normalize(Replay::new(scenario.trading).run(commands));
+        if let Some(diff) = replay::diff(&scenario.lines, &actual) {
+            failures.push(format!(
+                "{} (trading {}):\n{}",
+                scenario.name,
+                if scenario.trading { "on" } else { "off" },
+                diff
+            ));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} scenario(s) differ from the expected output (- expected, + actual):\n\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
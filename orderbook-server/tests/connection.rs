@@ -0,0 +1,253 @@
+//! Tests of client connections served together with the server loop over in-memory streams.
+
+use orderbook_common::{CancelOrder, Command, Logon, NewOrder, Response};
+use orderbook_engine::prelude::*;
+use orderbook_server::config::Config;
+use orderbook_server::connection::serve_connection;
+use orderbook_server::journal;
+use orderbook_server::server::{self, Request};
+
+use anyhow::Result;
+use futures::{Sink, SinkExt, Stream, TryStreamExt};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_serde::formats::SymmetricalJson;
+use tokio_serde::SymmetricallyFramed;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "orderbook-connection-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn order(user_id: u64, side: char) -> Command {
+    order_with_id(user_id, 1, side)
+}
+
+fn order_with_id(user_id: u64, user_order_id: u64, side: char) -> Command {
+    Command::New(NewOrder {
+        user_id,
+        user_order_id,
+        symbol: "IBM".to_string(),
+        price: 10,
+        quantity: 100,
+        side,
+        ..Default::default()
+    })
+}
+
+/// Start the server with a journal and a snapshot in temporary files.
+fn start_server(
+    name: &str,
+) -> (
+    mpsc::Sender<Request>,
+    JoinHandle<Result<()>>,
+    PathBuf,
+    PathBuf,
+) {
+    let journal_dir = temp_path(&format!("{}-journal", name));
+    let snapshot = temp_path(&format!("{}-snapshot", name));
+    let args = [
+        "--journal",
+        journal_dir.to_str().unwrap(),
+        "--snapshot",
+        snapshot.to_str().unwrap(),
+    ];
+    let config = Config::from_args(args.iter().map(ToString::to_string)).unwrap();
+    let (tx, rx) = mpsc::channel(32);
+    let server = tokio::spawn(server::run(rx, config));
+    (tx, server, journal_dir, snapshot)
+}
+
+/// Serve a connection of the session over an in-memory stream and return the client end of it:
+/// the sink of the commands and the stream of the responses.
+fn connect(
+    session: u64,
+    tx: mpsc::Sender<Request>,
+    shutdown: watch::Receiver<bool>,
+) -> (
+    impl Sink<Command, Error = io::Error> + Unpin,
+    impl Stream<Item = io::Result<Response>> + Unpin,
+    JoinHandle<()>,
+) {
+    let (client, socket) = tokio::io::duplex(1 << 16);
+    let (reader, writer) = tokio::io::split(socket);
+    let connection = tokio::spawn(serve_connection(session, reader, writer, tx, shutdown));
+    let (client_reader, client_writer) = tokio::io::split(client);
+    let commands = SymmetricallyFramed::new(
+        FramedWrite::new(client_writer, LengthDelimitedCodec::new()),
+        SymmetricalJson::<Command>::default(),
+    );
+    let responses = SymmetricallyFramed::new(
+        FramedRead::new(client_reader, LengthDelimitedCodec::new()),
+        SymmetricalJson::<Response>::default(),
+    );
+    (commands, responses, connection)
+}
+
+/// Restore the market from the snapshot and remove the files of the server.
+fn restore(journal_dir: PathBuf, snapshot: PathBuf) -> Market<FIFOMatcher> {
+    let market = Market::restore(FIFOMatcher, Snapshot::load(&snapshot).unwrap());
+    fs::remove_dir_all(journal_dir).unwrap();
+    fs::remove_file(snapshot).unwrap();
+    market
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_queued_commands() {
+    let (tx, server, journal_dir, snapshot) = start_server("drain");
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (mut commands, mut responses, connection) = connect(1, tx.clone(), shutdown_rx);
+
+    commands.send(order(1, 'S')).await.unwrap();
+    assert!(matches!(
+        responses.try_next().await.unwrap(),
+        Some(Response::Accepted { user_id: 1, .. })
+    ));
+    // A command another connection has queued right before the shutdown is still applied.
+    let (queued_tx, _queued_rx) = mpsc::unbounded_channel();
+    let queued = Request::Command {
+        session: 2,
+        command: Box::new(order(2, 'B')),
+        responses: queued_tx,
+    };
+    tx.send(queued).await.unwrap();
+    drop(tx);
+    shutdown_tx.send(true).unwrap();
+    assert!(matches!(
+        responses.try_next().await.unwrap(),
+        Some(Response::Shutdown)
+    ));
+    connection.await.unwrap();
+    server.await.unwrap().unwrap();
+
+    assert_eq!(journal::read(&journal_dir).unwrap().len(), 2);
+    assert_eq!(Snapshot::load(&snapshot).unwrap().position, 2);
+    let mut market = restore(journal_dir, snapshot);
+    let ibm = market.intern("IBM");
+    assert_eq!(market.position(1, ibm), -100);
+    assert_eq!(market.position(2, ibm), 100);
+}
+
+/// Log on with cancel on disconnect and the heartbeat, enter an order of user 1 and, once it is
+/// accepted, hang up or stay silent. Another order of user 1, entered through another session,
+/// stays on the market. Returns the market restored after the server has stopped.
+async fn disconnect(heartbeat_secs: Option<u64>, hang_up: bool) -> Market<FIFOMatcher> {
+    let name = format!("disconnect-{}", hang_up);
+    let (tx, server, journal_dir, snapshot) = start_server(&name);
+    let (responses_tx, mut other_responses) = mpsc::unbounded_channel();
+    let other = Request::Command {
+        session: 2,
+        command: Box::new(order_with_id(1, 2, 'S')),
+        responses: responses_tx,
+    };
+    tx.send(other).await.unwrap();
+    assert!(matches!(
+        other_responses.recv().await,
+        Some(Response::Accepted { user_id: 1, .. })
+    ));
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (mut commands, mut responses, connection) = connect(1, tx.clone(), shutdown_rx);
+    let logon = Command::Logon(Logon {
+        cancel_on_disconnect: true,
+        heartbeat_secs,
+    });
+    commands.send(logon).await.unwrap();
+    commands.send(order(1, 'S')).await.unwrap();
+    assert!(matches!(
+        responses.try_next().await.unwrap(),
+        Some(Response::Accepted { user_id: 1, .. })
+    ));
+    if hang_up {
+        drop(commands);
+        drop(responses);
+        connection.await.unwrap();
+    } else {
+        // The connection gives up on the silent client by itself once the heartbeat is missed.
+        connection.await.unwrap();
+        assert!(responses.try_next().await.unwrap().is_none());
+    }
+    drop(tx);
+    server.await.unwrap().unwrap();
+
+    let entries = journal::read(&journal_dir).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert!(matches!(
+        &entries[2].command,
+        Command::Cancel(CancelOrder {
+            user_id: 1,
+            user_order_id: 1,
+        })
+    ));
+    restore(journal_dir, snapshot)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_missed_heartbeat_cancels_orders() {
+    let mut market = disconnect(Some(5), false).await;
+    assert!(market.cancel(1, 1).is_none());
+    assert!(market.cancel(1, 2).is_some());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_hang_up_cancels_orders() {
+    let mut market = disconnect(None, true).await;
+    assert!(market.cancel(1, 1).is_none());
+    assert!(market.cancel(1, 2).is_some());
+}
+
+#[tokio::test]
+async fn test_rejected_orders_dont_join_the_session() {
+    let (tx, server, journal_dir, snapshot) = start_server("rejected");
+    // User 2 enters an order through another session.
+    let (responses_tx, mut other_responses) = mpsc::unbounded_channel();
+    let other = Request::Command {
+        session: 2,
+        command: Box::new(order(2, 'S')),
+        responses: responses_tx,
+    };
+    tx.send(other).await.unwrap();
+    assert!(matches!(
+        other_responses.recv().await,
+        Some(Response::Accepted { user_id: 2, .. })
+    ));
+
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (mut commands, mut responses, connection) = connect(1, tx.clone(), shutdown_rx);
+    let logon = Command::Logon(Logon {
+        cancel_on_disconnect: true,
+        heartbeat_secs: None,
+    });
+    commands.send(logon).await.unwrap();
+    // Neither an invalid order nor one the market rejects as a duplicate makes the orders of
+    // user 2 those of this session.
+    for side in ['X', 'S'] {
+        commands.send(order(2, side)).await.unwrap();
+        assert!(matches!(
+            responses.try_next().await.unwrap(),
+            Some(Response::Rejected { user_id: 2, .. })
+        ));
+    }
+    drop(commands);
+    drop(responses);
+    connection.await.unwrap();
+    drop(tx);
+    server.await.unwrap().unwrap();
+
+    let entries = journal::read(&journal_dir).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries
+        .iter()
+        .all(|entry| matches!(entry.command, Command::New(_))));
+    let mut market = restore(journal_dir, snapshot);
+    assert!(market.cancel(2, 1).is_some());
+}
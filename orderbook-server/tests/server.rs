@@ -0,0 +1,276 @@
+//! Tests of the server loop, driven through its request channel the way the connections do.
+
+use orderbook_common::{
+    AdminCommand, AdminRequest, Command, Deposit, NewOrder, Phase, PhaseChange, Response,
+};
+use orderbook_server::config::Config;
+use orderbook_server::replay::{self, Replay};
+use orderbook_server::server::{self, Request};
+
+use std::fs;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+const TOKEN: &str = "s3cret";
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("orderbook-server-{}-{}", name, std::process::id()))
+}
+
+/// Write the admin token to a file and return the server arguments pointing to it.
+fn token_args(name: &str) -> (Vec<String>, PathBuf) {
+    let path = temp_path(&format!("{}.token", name));
+    fs::write(&path, format!("{}\n", TOKEN)).unwrap();
+    let args = vec![
+        "--admin-token-file".to_string(),
+        path.to_string_lossy().into_owned(),
+    ];
+    (args, path)
+}
+
+/// Send the commands to a server started with the given arguments, let it finish and collect
+/// the responses to each of them.
+async fn run(args: Vec<String>, commands: Vec<Command>) -> Vec<Vec<Response>> {
+    let (tx, rx) = mpsc::channel(32);
+    let server = tokio::spawn(server::run(rx, Config::from_args(args).unwrap()));
+    let mut receivers = Vec::new();
+    for command in commands {
+        let (responses_tx, responses_rx) = mpsc::unbounded_channel();
+        tx.send(Request::Command {
+            session: 1,
+            command: Box::new(command),
+            responses: responses_tx,
+        })
+        .await
+        .unwrap();
+        receivers.push(responses_rx);
+    }
+    drop(tx);
+    server.await.unwrap().unwrap();
+    let mut responses = Vec::new();
+    for mut receiver in receivers {
+        let mut received = Vec::new();
+        while let Some(response) = receiver.recv().await {
+            received.push(response);
+        }
+        responses.push(received);
+    }
+    responses
+}
+
+/// Send the admin commands to a server started with the given arguments and collect the single
+/// response to each of them.
+async fn admin(args: Vec<String>, requests: Vec<AdminRequest>) -> Vec<Response> {
+    let commands = requests.into_iter().map(Command::Admin).collect();
+    run(args, commands)
+        .await
+        .into_iter()
+        .map(|mut responses| {
+            assert_eq!(responses.len(), 1);
+            responses.remove(0)
+        })
+        .collect()
+}
+
+fn halt(token: &str) -> AdminRequest {
+    AdminRequest {
+        token: token.to_string(),
+        command: AdminCommand::HaltMarket,
+    }
+}
+
+fn reasons(responses: &[Response]) -> Vec<Option<&str>> {
+    responses
+        .iter()
+        .map(|response| match response {
+            Response::AdminAck { .. } => None,
+            Response::AdminRejected { reason, .. } => Some(reason.as_str()),
+            _ => panic!("Unexpected response {:?}", response),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_admin_wrong_token() {
+    let (args, path) = token_args("wrong");
+    let responses = admin(args, vec![halt("guess"), halt(""), halt("s3cre")]).await;
+    assert_eq!(reasons(&responses), vec![Some("not authenticated"); 3]);
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_admin_without_configured_token() {
+    let responses = admin(Vec::new(), vec![halt(TOKEN), halt("")]).await;
+    assert_eq!(reasons(&responses), vec![Some("not authenticated"); 2]);
+}
+
+#[tokio::test]
+async fn test_admin_ack() {
+    let (args, path) = token_args("ack");
+    let resume = AdminRequest {
+        token: TOKEN.to_string(),
+        command: AdminCommand::ResumeMarket,
+    };
+    let responses = admin(args, vec![halt(TOKEN), halt(TOKEN), resume]).await;
+    assert_eq!(
+        reasons(&responses),
+        vec![None, Some("the market is already halted"), None]
+    );
+    assert!(matches!(
+        &responses[0],
+        Response::AdminAck {
+            command: AdminCommand::HaltMarket
+        }
+    ));
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_phase_changes_are_authenticated() {
+    let (args, path) = token_args("phase");
+    let phase = |token: &str, phase| AdminRequest {
+        token: token.to_string(),
+        command: AdminCommand::SetPhase(PhaseChange {
+            symbol: "IBM".to_string(),
+            phase,
+        }),
+    };
+    let responses = admin(
+        args,
+        vec![
+            phase("guess", Phase::Halted),
+            phase(TOKEN, Phase::Auction),
+            phase(TOKEN, Phase::PreOpen),
+        ],
+    )
+    .await;
+    assert_eq!(
+        reasons(&responses),
+        vec![
+            Some("not authenticated"),
+            None,
+            Some("IBM can't change to pre-open in its current phase")
+        ]
+    );
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_deposits_are_exported_to_the_ledger() {
+    let (mut args, path) = token_args("ledger");
+    let journal = temp_path("ledger-journal");
+    let ledger = temp_path("ledger.csv");
+    let _ = fs::remove_dir_all(&journal);
+    let _ = fs::remove_file(&ledger);
+    for arg in ["--funding", "USD", "--journal", journal.to_str().unwrap()] {
+        args.push(arg.to_string());
+    }
+    args.push("--ledger".to_string());
+    args.push(ledger.to_string_lossy().into_owned());
+    let deposit = |token: &str, user_id| AdminRequest {
+        token: token.to_string(),
+        command: AdminCommand::Deposit(Deposit {
+            user_id,
+            asset: "USD".to_string(),
+            amount: 100,
+        }),
+    };
+
+    let responses = admin(args.clone(), vec![deposit("guess", 1), deposit(TOKEN, 1)]).await;
+    assert_eq!(reasons(&responses), vec![Some("not authenticated"), None]);
+    // The deposit replayed from the journal isn't exported again, and the numbering of the
+    // entries carries on.
+    let responses = admin(args, vec![deposit(TOKEN, 2)]).await;
+    assert_eq!(reasons(&responses), vec![None]);
+    assert_eq!(
+        fs::read_to_string(&ledger).unwrap(),
+        "sequence, asset, debit, credit, amount\n\
+         1, USD, available:1, external, 100\n\
+         2, USD, available:2, external, 100\n"
+    );
+    fs::remove_dir_all(journal).unwrap();
+    fs::remove_file(ledger).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+fn order(user_id: u64, side: char) -> Command {
+    Command::New(NewOrder {
+        user_id,
+        user_order_id: 1,
+        symbol: "IBM".to_string(),
+        price: 10,
+        quantity: 100,
+        side,
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn test_order_responses() {
+    let fees = temp_path("fees.json");
+    fs::write(&fees, r#"{"default": {"maker": 0.001, "taker": 0.002}}"#).unwrap();
+    let args = vec!["--fees".to_string(), fees.to_string_lossy().into_owned()];
+    let responses = run(args, vec![order(1, 'S'), order(2, 'B')]).await;
+    assert!(matches!(
+        responses[0][..],
+        [Response::Accepted {
+            user_id: 1,
+            user_order_id: 1,
+            order_id: 1
+        }]
+    ));
+    match &responses[1][..] {
+        [Response::Accepted {
+            user_id: 2,
+            order_id: 2,
+            ..
+        }, Response::Trade {
+            trade_id: 1,
+            order_id_buy: 2,
+            order_id_sell: 1,
+            aggressor: Some('B'),
+            symbol,
+            fee_buy,
+            fee_sell,
+            ..
+        }] => {
+            assert_eq!(symbol, "IBM");
+            assert_eq!((*fee_buy, *fee_sell), (2.0, 1.0));
+        }
+        responses => panic!("Unexpected responses {:?}", responses),
+    }
+    fs::remove_file(fees).unwrap();
+}
+
+#[tokio::test]
+async fn test_replay_stamps_like_the_server() {
+    let journal = temp_path("stamps-journal");
+    let _ = fs::remove_dir_all(&journal);
+    let args = vec![
+        "--journal".to_string(),
+        journal.to_string_lossy().into_owned(),
+    ];
+    let responses = run(args, vec![order(1, 'S'), order(2, 'B')]).await;
+    let timestamp = match &responses[1][..] {
+        [_, Response::Trade { timestamp, .. }] => *timestamp,
+        responses => panic!("Unexpected responses {:?}", responses),
+    };
+    assert!(timestamp > 0);
+
+    let mut replay = Replay::new(true);
+    replay.set_sequenced(true);
+    let lines = replay::load(&journal)
+        .unwrap()
+        .into_iter()
+        .flat_map(|(time, command)| replay.apply_at(time, command))
+        .collect::<Vec<_>>();
+    let trade = lines
+        .iter()
+        .find(|line| line.split(", ").nth(2) == Some("T"))
+        .unwrap();
+    assert_eq!(
+        trade.split(", ").nth(1),
+        Some(timestamp.to_string().as_str())
+    );
+    fs::remove_dir_all(journal).unwrap();
+}